@@ -18,6 +18,28 @@ let
       export "info_$line"
     done < <(${pkgs.jq}/bin/jq -r <"$runner_info" \
              'to_entries|map("\(.key)=\(.value)\u0000")[]')
+
+    # Other inputs passed by ARC along with the JIT config
+    while read -rd $"" line
+    do
+      export "$line"
+    done < <(${pkgs.jq}/bin/jq -r <"$runner_info" \
+             '.inputs // {} | to_entries|map("\(.key)=\(.value)\u0000")[]')
+
+    # Extra environment passed with --runner-env
+    while read -rd $"" line
+    do
+      export "$line"
+    done < <(${pkgs.jq}/bin/jq -r <"$runner_info" \
+             '.env // {} | to_entries|map("\(.key)=\(.value)\u0000")[]')
+
+    # The runner honors both spellings
+    for var in http_proxy https_proxy no_proxy; do
+      value="info_$var"
+      if [[ -n "''${!value:-}" ]]; then
+        export "$var=''${!value}" "''${var^^}=''${!value}"
+      fi
+    done
   '';
 in {
   options = {
@@ -60,6 +82,30 @@ in {
       }
     ];
 
+    # Secrets and ConfigMaps passed with --mount
+    systemd.services.arc-runner-mounts = {
+      requiredBy = [ "arc-runner.service" ];
+      before = [ "arc-runner.service" ];
+      requires = [ "runner\\x2dinfo.mount" ];
+      after = [ "runner\\x2dinfo.mount" ];
+
+      path = with pkgs; [ coreutils util-linux ];
+
+      serviceConfig = {
+        Type = "oneshot";
+        RemainAfterExit = true;
+      };
+
+      script = ''
+        ${pkgs.jq}/bin/jq -r '.mounts // {} | to_entries[] | "\(.key) \(.value)"' \
+          </runner-info/runner-info.json |
+        while read -r tag path; do
+          mkdir -p "$path"
+          mount -t virtiofs "$tag" "$path"
+        done
+      '';
+    };
+
     systemd.services.arc-runner = {
       wantedBy = [ "multi-user.target" ];
       requires = [ "runner\\x2dinfo.mount" ];
@@ -0,0 +1,200 @@
+//! Config file support.
+//!
+//! The config file is a YAML mapping from option names (e.g.,
+//! `vm-template`) to values. The values are used as defaults,
+//! so environment variables and flags take precedence. Values for
+//! options that conflict with an environment variable or flag are
+//! ignored. Empty environment variables are treated as unset.
+//!
+//! Some options also accept the environment variable of another
+//! runner for compatibility, listed in `ENV_ALIASES`.
+
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Context, Result as AnyResult};
+use clap::{parser::ValueSource, Arg, ArgMatches, Command, Id, Parser};
+use serde_yaml::{Mapping, Value};
+
+/// The config file that is loaded if it exists.
+const DEFAULT_CONFIG_PATH: &str = "/etc/kubevirt-actions-runner/config.yaml";
+
+/// The ID of the argument specifying the config file.
+const CONFIG_ARG: &str = "config";
+
+/// Environment variables accepted for options whose own variable isn't set.
+const ENV_ALIASES: &[(&str, &str)] = &[
+    ("error_linger", "RUNNER_ERROR_HOLD_SECS"),
+    ("max_runtime", "RUNNER_MAX_RUNTIME"),
+];
+
+/// Parses options from the command line, environment and config file.
+pub fn parse<T: Parser>() -> AnyResult<T> {
+    parse_from(env::args_os().collect())
+}
+
+/// Parses options from `args`, the environment and config file.
+pub fn parse_from<T: Parser>(args: Vec<OsString>) -> AnyResult<T> {
+    let mut command = T::command();
+
+    // Manifests often set variables to empty strings instead of leaving them out
+    let unset: Vec<Id> = command
+        .get_arguments()
+        .filter(|arg| {
+            arg.get_env()
+                .and_then(env::var_os)
+                .is_some_and(|value| value.is_empty())
+        })
+        .map(|arg| arg.get_id().clone())
+        .collect();
+    for id in unset {
+        command = command.mut_arg(id, |arg| arg.env(None::<&str>));
+    }
+
+    for (id, alias) in ENV_ALIASES {
+        let Some(arg) = command.get_arguments().find(|arg| arg.get_id() == id) else {
+            continue;
+        };
+        let set = |name: &OsStr| env::var_os(name).is_some_and(|value| !value.is_empty());
+        if !arg.get_env().is_some_and(set) && set(OsStr::new(alias)) {
+            command = command.mut_arg(id, |arg| arg.env(alias));
+        }
+    }
+
+    // First pass to find out where the config file is, and which
+    // options are set by higher-precedence sources
+    let matches = command
+        .clone()
+        .ignore_errors(true)
+        .get_matches_from(args.clone());
+    let (path, explicit) = match matches.get_one::<PathBuf>(CONFIG_ARG) {
+        Some(path) => (path.clone(), true),
+        None => (PathBuf::from(DEFAULT_CONFIG_PATH), false),
+    };
+
+    let config = match fs::read_to_string(&path) {
+        Ok(config) => Some(config),
+        Err(e) if !explicit && e.kind() == ErrorKind::NotFound => None,
+        Err(e) => {
+            return Err(e).with_context(|| format!("Failed to read config file {}", path.display()))
+        }
+    };
+
+    if let Some(config) = config {
+        let config: Mapping = serde_yaml::from_str(&config)
+            .with_context(|| format!("Failed to parse config file {}", path.display()))?;
+
+        for (key, value) in config {
+            let key = key
+                .as_str()
+                .ok_or_else(|| anyhow!("Config keys must be strings"))?
+                .to_string();
+            let arg = command
+                .get_arguments()
+                .find(|arg| arg.get_long() == Some(key.as_str()))
+                .filter(|arg| arg.get_id() != CONFIG_ARG)
+                .ok_or_else(|| anyhow!("Unknown option in config file: {}", key))?;
+            let id = arg.get_id().clone();
+            let values = config_values(&key, value)?;
+
+            // Defaults don't take part in conflict checks
+            if let Some(conflict) = conflicting_ids(&command, arg)
+                .into_iter()
+                .find(|conflict| is_explicit(&matches, conflict))
+            {
+                tracing::debug!(
+                    "Ignoring {} from the config file since {} is set",
+                    key,
+                    conflict
+                );
+                continue;
+            }
+
+            command = command.mut_arg(id, |arg| arg.default_values(values).required(false));
+        }
+    }
+
+    let matches = command.get_matches_from(args);
+    Ok(T::from_arg_matches(&matches)?)
+}
+
+/// Parses the default options, ignoring the environment and config file.
+pub fn defaults<T: Parser>() -> AnyResult<T> {
+    let mut command = T::command();
+    let ids: Vec<Id> = command
+        .get_arguments()
+        .map(|arg| arg.get_id().clone())
+        .collect();
+    for id in ids {
+        command = command.mut_arg(id, |arg| arg.env(None::<&str>));
+    }
+
+    let bin = command.get_name().to_string();
+    let matches = command.try_get_matches_from([bin])?;
+    Ok(T::from_arg_matches(&matches)?)
+}
+
+/// Returns the arguments that conflict with `arg`, in either direction.
+fn conflicting_ids(command: &Command, arg: &Arg) -> Vec<Id> {
+    let mut ids: Vec<Id> = command
+        .get_arg_conflicts_with(arg)
+        .into_iter()
+        .map(|conflict| conflict.get_id().clone())
+        .collect();
+
+    for other in command.get_arguments() {
+        let conflicts = command.get_arg_conflicts_with(other);
+        if conflicts.iter().any(|c| c.get_id() == arg.get_id()) {
+            ids.push(other.get_id().clone());
+        }
+    }
+
+    ids
+}
+
+/// Returns whether an argument is set by a flag or environment variable.
+fn is_explicit(matches: &ArgMatches, id: &Id) -> bool {
+    matches!(
+        matches.value_source(id.as_str()),
+        Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+    )
+}
+
+/// Converts a config value to argument values.
+///
+/// Sequences turn into multiple values, and mappings turn into
+/// multiple `KEY=VALUE` values.
+fn config_values(key: &str, value: Value) -> AnyResult<Vec<OsString>> {
+    match value {
+        Value::Sequence(items) => items
+            .into_iter()
+            .map(|item| config_value(key, item))
+            .collect(),
+        Value::Mapping(map) => map
+            .into_iter()
+            .map(|(k, v)| {
+                let k = config_value(key, k)?;
+                let v = config_value(key, v)?;
+
+                let mut kv = k;
+                kv.push("=");
+                kv.push(v);
+                Ok(kv)
+            })
+            .collect(),
+        value => Ok(vec![config_value(key, value)?]),
+    }
+}
+
+/// Converts a scalar config value to an argument value.
+fn config_value(key: &str, value: Value) -> AnyResult<OsString> {
+    match value {
+        Value::String(s) => Ok(s.into()),
+        Value::Bool(b) => Ok(b.to_string().into()),
+        Value::Number(n) => Ok(n.to_string().into()),
+        _ => Err(anyhow!("Invalid value for {} in config file", key)),
+    }
+}
@@ -0,0 +1,126 @@
+//! Serial console streaming.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, Result as AnyResult};
+use futures::StreamExt;
+use hyper::{header, Body, Request, StatusCode};
+use kube::Client;
+use tokio_tungstenite::{
+    tungstenite::{handshake::client::generate_key, protocol::Role, Message},
+    WebSocketStream,
+};
+
+/// The subprotocol spoken by the KubeVirt console subresource.
+const CONSOLE_PROTOCOL: &str = "plain.kubevirt.io";
+
+/// How many lines of the serial console to keep by default.
+pub const DEFAULT_TAIL_LINES: usize = 200;
+
+/// The last lines of the serial console.
+#[derive(Debug, Clone)]
+pub struct ConsoleTail {
+    lines: Arc<Mutex<VecDeque<String>>>,
+    capacity: usize,
+}
+
+impl ConsoleTail {
+    /// Creates a buffer keeping the last `capacity` lines.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            lines: Default::default(),
+            capacity,
+        }
+    }
+
+    fn push(&self, line: String) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let mut lines = self.lines.lock().unwrap();
+        if lines.len() == self.capacity {
+            lines.pop_front();
+        }
+        lines.push_back(line);
+    }
+
+    /// Returns the lines we have kept.
+    pub fn lines(&self) -> Vec<String> {
+        self.lines.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+/// Reads the serial console of a VMI.
+///
+/// The last lines are kept in `tail`. If `relay` is set, each line
+/// is also logged with the `console` target. This returns when the
+/// console connection is closed.
+pub async fn stream_console(
+    client: Client,
+    namespace: &str,
+    name: &str,
+    tail: &ConsoleTail,
+    relay: bool,
+) -> AnyResult<()> {
+    // We can't use `Client::connect` because it insists on the
+    // `v4.channel.k8s.io` subprotocol
+    let key = generate_key();
+    let request = Request::get(format!(
+        "/apis/subresources.kubevirt.io/v1/namespaces/{}/virtualmachineinstances/{}/console",
+        namespace, name
+    ))
+    .header(header::CONNECTION, "Upgrade")
+    .header(header::UPGRADE, "websocket")
+    .header(header::SEC_WEBSOCKET_VERSION, "13")
+    .header(header::SEC_WEBSOCKET_KEY, key)
+    .header(header::SEC_WEBSOCKET_PROTOCOL, CONSOLE_PROTOCOL)
+    .body(Body::empty())?;
+
+    let response = client.send(request).await?;
+    if response.status() != StatusCode::SWITCHING_PROTOCOLS {
+        return Err(anyhow!(
+            "Unexpected response from the console subresource: {}",
+            response.status()
+        ));
+    }
+
+    let upgraded = hyper::upgrade::on(response).await?;
+    let mut ws = WebSocketStream::from_raw_socket(upgraded, Role::Client, None).await;
+
+    tracing::info!("Connected to the serial console");
+
+    let mut buf = Vec::new();
+    while let Some(message) = ws.next().await {
+        let data = match message? {
+            Message::Binary(data) => data,
+            Message::Text(text) => text.into_bytes(),
+            Message::Close(_) => break,
+            _ => continue,
+        };
+
+        buf.extend_from_slice(&data);
+        while let Some(pos) = buf.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = buf.drain(..=pos).collect();
+            handle_line(&line, tail, relay);
+        }
+    }
+
+    if !buf.is_empty() {
+        handle_line(&buf, tail, relay);
+    }
+
+    tracing::info!("The serial console was disconnected");
+
+    Ok(())
+}
+
+fn handle_line(line: &[u8], tail: &ConsoleTail, relay: bool) {
+    let line = String::from_utf8_lossy(line);
+    let line = line.trim_end_matches(['\r', '\n']);
+    if relay {
+        tracing::info!(target: "console", "{}", line);
+    }
+    tail.push(line.to_string());
+}
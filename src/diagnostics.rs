@@ -0,0 +1,99 @@
+//! Capturing diagnostics of failed VMIs.
+
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Result as AnyResult};
+use k8s_openapi::api::core::v1::{Event, Pod};
+use kube::{
+    api::{Api, ListParams},
+    ResourceExt,
+};
+use serde::Serialize;
+
+use crate::{console::ConsoleTail, VirtualMachineInstance};
+
+/// Writes everything we know about a VMI to a directory named after it under `dir`.
+///
+/// This includes the VMI, its virt-launcher pods, the events of both,
+/// and the last lines of the serial console if it was streamed.
+/// Parts that cannot be captured are skipped with a warning.
+pub async fn capture(
+    dir: &Path,
+    vmis: &Api<VirtualMachineInstance>,
+    pods: &Api<Pod>,
+    events: &Api<Event>,
+    name: &str,
+    console: Option<&ConsoleTail>,
+) -> AnyResult<()> {
+    let dir = dir.join(name);
+    fs::create_dir_all(&dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+
+    tracing::info!("Capturing diagnostics to {}", dir.display());
+
+    let mut uids = Vec::new();
+    match vmis.get_opt(name).await {
+        Ok(Some(vmi)) => {
+            uids.extend(vmi.uid());
+            write_yaml(&dir, "vmi.yaml", &vmi);
+        }
+        Ok(None) => {
+            tracing::warn!("The VMI is gone - Not capturing it");
+        }
+        Err(e) => {
+            tracing::warn!("Failed to get the VMI: {}", e);
+        }
+    }
+
+    if let Some(vmi_uid) = uids.first().cloned() {
+        let lp = ListParams::default().labels(&format!("kubevirt.io/created-by={}", vmi_uid));
+        match pods.list(&lp).await {
+            Ok(launchers) => {
+                uids.extend(launchers.iter().filter_map(|pod| pod.uid()));
+                write_yaml(&dir, "virt-launcher.yaml", &launchers.items);
+            }
+            Err(e) => {
+                tracing::warn!("Failed to list virt-launcher pods: {}", e);
+            }
+        }
+    }
+
+    let mut related = Vec::new();
+    for uid in &uids {
+        let lp = ListParams::default().fields(&format!("involvedObject.uid={}", uid));
+        match events.list(&lp).await {
+            Ok(list) => related.extend(list.items),
+            Err(e) => {
+                tracing::warn!("Failed to list events: {}", e);
+                break;
+            }
+        }
+    }
+    if !related.is_empty() {
+        write_yaml(&dir, "events.yaml", &related);
+    }
+
+    if let Some(console) = console {
+        let mut lines = console.lines().join("\n");
+        lines.push('\n');
+        write_file(&dir, "console.log", lines.as_bytes());
+    }
+
+    Ok(())
+}
+
+fn write_yaml<T: Serialize>(dir: &Path, file: &str, value: &T) {
+    match serde_yaml::to_string(value) {
+        Ok(yaml) => write_file(dir, file, yaml.as_bytes()),
+        Err(e) => {
+            tracing::warn!("Failed to serialize {}: {}", file, e);
+        }
+    }
+}
+
+fn write_file(dir: &Path, file: &str, contents: &[u8]) {
+    let path = dir.join(file);
+    if let Err(e) = fs::write(&path, contents) {
+        tracing::warn!("Failed to write {}: {}", path.display(), e);
+    }
+}
@@ -0,0 +1,61 @@
+//! Errors of a runner.
+
+use thiserror::Error;
+
+use crate::VmiOutcome;
+
+/// Why a runner has failed.
+///
+/// Errors wrapping an [`anyhow::Error`] are displayed as-is, so
+/// the message is the same as that of the underlying error.
+#[derive(Debug, Error)]
+pub enum RunnerError {
+    /// The options or the environment are invalid.
+    #[error(transparent)]
+    Config(anyhow::Error),
+
+    /// The VM template could not be fetched.
+    #[error(transparent)]
+    Template(anyhow::Error),
+
+    /// The VMI could not be created (or the VM could not be started).
+    #[error(transparent)]
+    Create(anyhow::Error),
+
+    /// Watching the VMI has failed.
+    #[error(transparent)]
+    Watch(anyhow::Error),
+
+    /// The VMI has ended abnormally.
+    #[error("{message}")]
+    Outcome {
+        outcome: VmiOutcome,
+        message: String,
+    },
+
+    /// A request to the Kubernetes API has failed.
+    #[error(transparent)]
+    Kube(#[from] kube::Error),
+
+    /// Any other error.
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
+impl RunnerError {
+    /// Returns the exit code of the process.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::Outcome { outcome, .. } => outcome.exit_code(),
+            _ => 1,
+        }
+    }
+
+    /// Returns the outcome of the VMI if it has ended abnormally.
+    pub fn outcome(&self) -> Option<&VmiOutcome> {
+        match self {
+            Self::Outcome { outcome, .. } => Some(outcome),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,103 @@
+//! Kubernetes Events.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use k8s_openapi::api::core::v1::ObjectReference;
+use kube::{
+    runtime::events::{Event, EventType, Recorder, Reporter},
+    Client,
+};
+
+const REPORTER: &str = "kubevirt-actions-runner";
+
+/// Publishes Kubernetes Events for the VMI lifecycle.
+///
+/// Events are attached to the runner pod if we know it, with the
+/// VMI as the related object. Otherwise, they are attached to the
+/// VMI directly.
+///
+/// Failing to publish an event never fails the run. If we
+/// aren't allowed to create events, we log a warning once and
+/// stop trying.
+pub struct EventPublisher {
+    recorder: Option<Recorder>,
+    related: Option<ObjectReference>,
+    forbidden: AtomicBool,
+}
+
+impl EventPublisher {
+    /// Creates a publisher for events about a VMI.
+    pub fn new(
+        client: Client,
+        pod_name: Option<&str>,
+        pod_uid: Option<&str>,
+        vmi: ObjectReference,
+    ) -> Self {
+        let reporter = Reporter {
+            controller: REPORTER.to_string(),
+            instance: pod_name.map(str::to_string),
+        };
+
+        let (regarding, related) = if let Some(pod_name) = pod_name {
+            let pod = ObjectReference {
+                api_version: Some("v1".to_string()),
+                kind: Some("Pod".to_string()),
+                name: Some(pod_name.to_string()),
+                namespace: vmi.namespace.clone(),
+                uid: pod_uid.map(str::to_string),
+                ..Default::default()
+            };
+            (pod, Some(vmi))
+        } else {
+            (vmi, None)
+        };
+
+        Self {
+            recorder: Some(Recorder::new(client, reporter, regarding)),
+            related,
+            forbidden: AtomicBool::new(false),
+        }
+    }
+
+    /// Creates a publisher that doesn't publish anything.
+    pub fn disabled() -> Self {
+        Self {
+            recorder: None,
+            related: None,
+            forbidden: AtomicBool::new(false),
+        }
+    }
+
+    /// Publishes an event.
+    pub async fn publish(&self, type_: EventType, reason: &str, note: String) {
+        let Some(recorder) = &self.recorder else {
+            return;
+        };
+
+        if self.forbidden.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let event = Event {
+            type_,
+            reason: reason.to_string(),
+            note: Some(note),
+            action: "WatchVMI".to_string(),
+            secondary: self.related.clone(),
+        };
+
+        match recorder.publish(event).await {
+            Ok(()) => {}
+            Err(kube::Error::Api(e)) if e.code == 403 => {
+                tracing::warn!(
+                    "Not allowed to create events - Disabling events: {}",
+                    e.message
+                );
+                self.forbidden.store(true, Ordering::Relaxed);
+            }
+            Err(e) => {
+                tracing::warn!("Failed to publish {} event: {}", reason, e);
+            }
+        }
+    }
+}
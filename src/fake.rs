@@ -0,0 +1,462 @@
+//! A fake Kubernetes API server for tests.
+//!
+//! It only serves what we use: the discovery of `kubevirt.io`, and
+//! getting, listing, watching, creating, patching and deleting objects
+//! by path. Objects are deleted right away, as if they had no
+//! finalizers.
+
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use hyper::{Body, Method, Request, Response, StatusCode};
+use k8s_openapi::chrono::{SecondsFormat, Utc};
+use kube::Client;
+use serde_json::{json, Value};
+use tokio::sync::mpsc;
+
+/// The namespace everything is in.
+pub(crate) const NAMESPACE: &str = "default";
+
+pub(crate) const VMS: &str = "/apis/kubevirt.io/v1/namespaces/default/virtualmachines";
+pub(crate) const VMIS: &str = "/apis/kubevirt.io/v1/namespaces/default/virtualmachineinstances";
+pub(crate) const PODS: &str = "/api/v1/namespaces/default/pods";
+pub(crate) const SECRETS: &str = "/api/v1/namespaces/default/secrets";
+
+/// A fake API server backed by an in-memory store.
+///
+/// Clones share the same store.
+#[derive(Clone, Default)]
+pub(crate) struct FakeCluster {
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Default)]
+struct Inner {
+    /// Objects by collection path and name.
+    objects: BTreeMap<String, BTreeMap<String, Value>>,
+
+    /// Every change so far, to replay to watches that start late.
+    history: Vec<Change>,
+
+    watches: Vec<Watch>,
+    resource_version: u64,
+
+    /// Whether watches fail instead of reporting changes.
+    fail_watches: bool,
+}
+
+struct Change {
+    collection: String,
+    resource_version: u64,
+    type_: &'static str,
+    object: Value,
+}
+
+struct Watch {
+    collection: String,
+    name: Option<String>,
+    events: mpsc::UnboundedSender<String>,
+}
+
+impl FakeCluster {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns a client talking to us.
+    pub(crate) fn client(&self) -> Client {
+        let cluster = self.clone();
+        let service = tower::service_fn(move |request: Request<Body>| {
+            let cluster = cluster.clone();
+            async move { Ok::<_, Infallible>(cluster.handle(request).await) }
+        });
+        Client::new(service, NAMESPACE)
+    }
+
+    /// Makes watches fail from now on.
+    pub(crate) fn fail_watches(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.fail_watches = true;
+        for watch in inner.watches.drain(..) {
+            watch.events.send(watch_error()).ok();
+        }
+    }
+
+    /// Adds an object to `collection`.
+    pub(crate) fn insert(&self, collection: &str, object: Value) -> Value {
+        self.inner
+            .lock()
+            .unwrap()
+            .create(collection, object)
+            .unwrap()
+    }
+
+    /// Returns an object in `collection`, if it exists.
+    pub(crate) fn get(&self, collection: &str, name: &str) -> Option<Value> {
+        let inner = self.inner.lock().unwrap();
+        inner.objects.get(collection)?.get(name).cloned()
+    }
+
+    /// Merges `patch` into an object in `collection`.
+    pub(crate) fn patch(&self, collection: &str, name: &str, patch: &Value) {
+        self.inner
+            .lock()
+            .unwrap()
+            .patch(collection, name, patch)
+            .unwrap();
+    }
+
+    /// Sets the phase of a VMI.
+    pub(crate) fn set_phase(&self, name: &str, phase: &str) {
+        self.patch(VMIS, name, &json!({ "status": { "phase": phase } }));
+    }
+
+    /// Returns whether anything is watching an object in `collection`.
+    pub(crate) fn is_watched(&self, collection: &str, name: &str) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        inner.watches.retain(|watch| !watch.events.is_closed());
+        inner.watches.iter().any(|watch| {
+            watch.collection == collection && watch.name.as_deref().is_none_or(|n| n == name)
+        })
+    }
+
+    /// Waits until `condition` holds, panicking after a while.
+    pub(crate) async fn wait_until(&self, mut condition: impl FnMut(&Self) -> bool) {
+        for _ in 0..500 {
+            if condition(self) {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("Condition did not hold within 5s");
+    }
+
+    async fn handle(&self, request: Request<Body>) -> Response<Body> {
+        let (parts, body) = request.into_parts();
+        let body = hyper::body::to_bytes(body).await.unwrap_or_default();
+        let body: Value = serde_json::from_slice(&body).unwrap_or(Value::Null);
+
+        let path = parts.uri.path().trim_end_matches('/').to_string();
+        let query: BTreeMap<String, String> =
+            form_urlencoded::parse(parts.uri.query().unwrap_or_default().as_bytes())
+                .into_owned()
+                .collect();
+
+        match path.as_str() {
+            "/apis" => return respond(StatusCode::OK, api_groups()),
+            "/apis/kubevirt.io/v1" => return respond(StatusCode::OK, kubevirt_resources()),
+            _ => {}
+        }
+
+        // Collections are always namespaced
+        let segments: Vec<&str> = path.split('/').collect();
+        let Some(i) = segments.iter().position(|s| *s == "namespaces") else {
+            return status(StatusCode::NOT_FOUND, "NotFound", &path);
+        };
+        if segments.len() < i + 3 {
+            return status(StatusCode::NOT_FOUND, "NotFound", &path);
+        }
+        let collection = segments[..i + 3].join("/");
+        let name = segments.get(i + 3).copied();
+
+        let mut inner = self.inner.lock().unwrap();
+        let result = match (parts.method, name) {
+            (Method::GET, Some(name)) => inner
+                .objects
+                .get(&collection)
+                .and_then(|objects| objects.get(name))
+                .cloned()
+                .map(|object| (StatusCode::OK, object))
+                .ok_or(StatusCode::NOT_FOUND),
+            (Method::GET, None) if query.get("watch").is_some_and(|w| w == "true") => {
+                return inner.watch(&collection, &query);
+            }
+            (Method::GET, None) => Ok((StatusCode::OK, inner.list(&collection, &query))),
+            (Method::POST, None) => inner
+                .create(&collection, body)
+                .map(|object| (StatusCode::CREATED, object)),
+            (Method::PATCH, Some(name)) => inner
+                .patch(&collection, name, &body)
+                .map(|object| (StatusCode::OK, object)),
+            (Method::DELETE, Some(name)) => inner
+                .delete(&collection, name)
+                .map(|object| (StatusCode::OK, object)),
+            (method, _) => panic!("Unexpected {} {}", method, path),
+        };
+
+        match result {
+            Ok((code, object)) => respond(code, object),
+            Err(StatusCode::NOT_FOUND) => status(StatusCode::NOT_FOUND, "NotFound", &path),
+            Err(StatusCode::CONFLICT) => status(StatusCode::CONFLICT, "AlreadyExists", &path),
+            Err(code) => status(code, "InternalError", &path),
+        }
+    }
+}
+
+impl Inner {
+    fn next_resource_version(&mut self) -> String {
+        self.resource_version += 1;
+        self.resource_version.to_string()
+    }
+
+    /// Records a change and sends it to the watches interested in it.
+    fn record(&mut self, collection: &str, type_: &'static str, object: &Value) {
+        let change = Change {
+            collection: collection.to_string(),
+            resource_version: self.resource_version,
+            type_,
+            object: object.clone(),
+        };
+
+        self.watches
+            .retain(|watch| !watch.matches(&change) || watch.events.send(change.to_line()).is_ok());
+        self.history.push(change);
+    }
+
+    fn list(&self, collection: &str, query: &BTreeMap<String, String>) -> Value {
+        let items: Vec<Value> = self
+            .objects
+            .get(collection)
+            .into_iter()
+            .flat_map(|objects| objects.values())
+            .filter(|object| matches_selectors(object, query))
+            .cloned()
+            .collect();
+
+        json!({
+            "apiVersion": "v1",
+            "kind": "List",
+            "metadata": { "resourceVersion": self.resource_version.to_string() },
+            "items": items,
+        })
+    }
+
+    fn watch(&mut self, collection: &str, query: &BTreeMap<String, String>) -> Response<Body> {
+        let (events, mut rx) = mpsc::unbounded_channel();
+        if self.fail_watches {
+            events.send(watch_error()).ok();
+        } else {
+            let watch = Watch {
+                collection: collection.to_string(),
+                name: query
+                    .get("fieldSelector")
+                    .and_then(|selector| selector.strip_prefix("metadata.name="))
+                    .map(str::to_string),
+                events,
+            };
+
+            // Changes since the list the watcher started from
+            let since: u64 = query
+                .get("resourceVersion")
+                .and_then(|rv| rv.parse().ok())
+                .unwrap_or(0);
+            for change in &self.history {
+                if change.resource_version > since && watch.matches(change) {
+                    watch.events.send(change.to_line()).ok();
+                }
+            }
+            self.watches.push(watch);
+        }
+
+        let (mut sender, body) = Body::channel();
+        tokio::spawn(async move {
+            while let Some(line) = rx.recv().await {
+                if sender.send_data(line.into()).await.is_err() {
+                    break;
+                }
+            }
+        });
+        Response::new(body)
+    }
+
+    fn create(&mut self, collection: &str, mut object: Value) -> Result<Value, StatusCode> {
+        let metadata = &mut object["metadata"];
+        if metadata.get("name").is_none() {
+            let prefix = metadata["generateName"].as_str().unwrap_or_default();
+            metadata["name"] = format!("{}{:05}", prefix, self.resource_version).into();
+        }
+        let name = metadata["name"].as_str().unwrap_or_default().to_string();
+
+        if self
+            .objects
+            .get(collection)
+            .is_some_and(|objects| objects.contains_key(&name))
+        {
+            return Err(StatusCode::CONFLICT);
+        }
+
+        let resource_version = self.next_resource_version();
+        let metadata = &mut object["metadata"];
+        metadata["namespace"] = NAMESPACE.into();
+        metadata["resourceVersion"] = resource_version.into();
+        if metadata.get("uid").is_none() {
+            metadata["uid"] = format!("uid-{}", self.resource_version).into();
+        }
+        if metadata.get("creationTimestamp").is_none() {
+            metadata["creationTimestamp"] =
+                Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true).into();
+        }
+
+        self.objects
+            .entry(collection.to_string())
+            .or_default()
+            .insert(name, object.clone());
+        self.record(collection, "ADDED", &object);
+        Ok(object)
+    }
+
+    fn patch(&mut self, collection: &str, name: &str, patch: &Value) -> Result<Value, StatusCode> {
+        let resource_version = self.next_resource_version();
+        let object = self
+            .objects
+            .get_mut(collection)
+            .and_then(|objects| objects.get_mut(name))
+            .ok_or(StatusCode::NOT_FOUND)?;
+
+        merge(object, patch);
+        object["metadata"]["resourceVersion"] = resource_version.into();
+
+        let object = object.clone();
+        self.record(collection, "MODIFIED", &object);
+        Ok(object)
+    }
+
+    fn delete(&mut self, collection: &str, name: &str) -> Result<Value, StatusCode> {
+        let object = self
+            .objects
+            .get_mut(collection)
+            .and_then(|objects| objects.remove(name))
+            .ok_or(StatusCode::NOT_FOUND)?;
+
+        self.next_resource_version();
+        self.record(collection, "DELETED", &object);
+        Ok(object)
+    }
+}
+
+impl Watch {
+    fn matches(&self, change: &Change) -> bool {
+        self.collection == change.collection
+            && self
+                .name
+                .as_deref()
+                .is_none_or(|name| change.object["metadata"]["name"] == name)
+    }
+}
+
+impl Change {
+    fn to_line(&self) -> String {
+        format!("{}\n", json!({ "type": self.type_, "object": self.object }))
+    }
+}
+
+/// Applies a JSON merge patch.
+fn merge(target: &mut Value, patch: &Value) {
+    let Value::Object(patch) = patch else {
+        *target = patch.clone();
+        return;
+    };
+
+    if !target.is_object() {
+        *target = json!({});
+    }
+    for (key, value) in patch {
+        if value.is_null() {
+            target.as_object_mut().unwrap().remove(key);
+        } else {
+            merge(&mut target[key], value);
+        }
+    }
+}
+
+/// Returns whether an object matches the `metadata.name` field
+/// selector and the equality-based label selector in `query`.
+fn matches_selectors(object: &Value, query: &BTreeMap<String, String>) -> bool {
+    let name_matches = query
+        .get("fieldSelector")
+        .and_then(|selector| selector.strip_prefix("metadata.name="))
+        .is_none_or(|name| object["metadata"]["name"] == name);
+
+    let labels_match = query.get("labelSelector").is_none_or(|selector| {
+        selector.split(',').all(|requirement| {
+            let (key, value) = requirement.split_once('=').unwrap_or((requirement, ""));
+            let label = &object["metadata"]["labels"][key.trim()];
+            match value {
+                "" => !label.is_null(),
+                value => label == value.trim(),
+            }
+        })
+    });
+
+    name_matches && labels_match
+}
+
+fn watch_error() -> String {
+    let status = json!({
+        "apiVersion": "v1",
+        "kind": "Status",
+        "status": "Failure",
+        "reason": "InternalError",
+        "message": "Watch failed",
+        "code": 500,
+    });
+    format!("{}\n", json!({ "type": "ERROR", "object": status }))
+}
+
+fn respond(code: StatusCode, body: Value) -> Response<Body> {
+    let mut response = Response::new(Body::from(body.to_string()));
+    *response.status_mut() = code;
+    response
+}
+
+fn status(code: StatusCode, reason: &str, path: &str) -> Response<Body> {
+    respond(
+        code,
+        json!({
+            "apiVersion": "v1",
+            "kind": "Status",
+            "status": "Failure",
+            "reason": reason,
+            "message": format!("{} {}", reason, path),
+            "code": code.as_u16(),
+        }),
+    )
+}
+
+fn api_groups() -> Value {
+    json!({
+        "kind": "APIGroupList",
+        "apiVersion": "v1",
+        "groups": [
+            {
+                "name": "kubevirt.io",
+                "versions": [{ "groupVersion": "kubevirt.io/v1", "version": "v1" }],
+                "preferredVersion": { "groupVersion": "kubevirt.io/v1", "version": "v1" },
+            },
+        ],
+    })
+}
+
+fn kubevirt_resources() -> Value {
+    let resource = |name: &str, kind: &str| {
+        json!({
+            "name": name,
+            "singularName": kind.to_lowercase(),
+            "namespaced": true,
+            "kind": kind,
+            "verbs": ["create", "delete", "get", "list", "patch", "update", "watch"],
+        })
+    };
+
+    json!({
+        "kind": "APIResourceList",
+        "apiVersion": "v1",
+        "groupVersion": "kubevirt.io/v1",
+        "resources": [
+            resource("virtualmachines", "VirtualMachine"),
+            resource("virtualmachineinstances", "VirtualMachineInstance"),
+        ],
+    })
+}
@@ -0,0 +1,124 @@
+//! Garbage collection of orphaned VMIs.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result as AnyResult};
+use k8s_openapi::api::core::v1::Pod;
+use kube::{
+    api::{Api, DeleteParams, ListParams},
+    ResourceExt,
+};
+
+use crate::{VirtualMachineInstance, MANAGED_BY, MANAGED_BY_LABEL, OWNER_POD_LABEL};
+
+/// Deletes runner VMIs older than `older_than` whose runner pod is gone.
+///
+/// Runner VMIs are recognized with `is_runner_vmi`. Unless `confirm`
+/// is set, the VMIs are only printed.
+pub async fn collect_garbage(
+    vmis: &Api<VirtualMachineInstance>,
+    pods: &Api<Pod>,
+    annotation: &str,
+    older_than: Duration,
+    confirm: bool,
+) -> AnyResult<()> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+    let candidates = vmis
+        .list(&ListParams::default())
+        .await
+        .context("Failed to list VMIs")?;
+
+    let mut orphaned = 0;
+    for vmi in candidates {
+        if !is_runner_vmi(&vmi, annotation) {
+            continue;
+        }
+
+        let age = vmi
+            .metadata
+            .creation_timestamp
+            .as_ref()
+            .map(|t| now - t.0.timestamp())
+            .unwrap_or_default();
+        if age < older_than.as_secs() as i64 {
+            continue;
+        }
+
+        let (owner, owner_uid) = owner_pod(&vmi);
+        let pod = pods
+            .get_opt(&owner)
+            .await
+            .with_context(|| format!("Failed to get pod {}", owner))?;
+        let owner_exists = match (pod, owner_uid) {
+            (Some(pod), Some(uid)) => pod.metadata.uid.as_deref() == Some(uid.as_str()),
+            (Some(_), None) => true,
+            (None, _) => false,
+        };
+
+        if owner_exists {
+            continue;
+        }
+
+        orphaned += 1;
+        let name = vmi.name_any();
+        if !confirm {
+            println!(
+                "Would delete VMI {} (age {}s, pod {} is gone)",
+                name, age, owner
+            );
+            continue;
+        }
+
+        println!(
+            "Deleting VMI {} (age {}s, pod {} is gone)",
+            name, age, owner
+        );
+        match vmis.delete(&name, &DeleteParams::default()).await {
+            Ok(_) => {}
+            Err(kube::Error::Api(e)) if e.code == 404 => {}
+            Err(e) => {
+                tracing::warn!("Failed to delete VMI {}: {}", name, e);
+            }
+        }
+    }
+
+    if !confirm && orphaned > 0 {
+        println!("Pass --confirm to delete {} VMI(s)", orphaned);
+    }
+
+    Ok(())
+}
+
+/// Returns whether the VMI was created by us.
+///
+/// VMIs created by older versions only have the runner info
+/// annotation or the owner pod label.
+pub fn is_runner_vmi(vmi: &VirtualMachineInstance, annotation: &str) -> bool {
+    vmi.labels().get(MANAGED_BY_LABEL).map(String::as_str) == Some(MANAGED_BY)
+        || vmi.annotations().contains_key(annotation)
+        || vmi.labels().contains_key(OWNER_POD_LABEL)
+}
+
+/// Returns the name and UID (if known) of the runner pod of a VMI.
+///
+/// If the VMI has neither the owner pod label nor an owner
+/// reference, the runner pod is assumed to have the name of
+/// the VMI.
+fn owner_pod(vmi: &VirtualMachineInstance) -> (String, Option<String>) {
+    let owner_ref = vmi
+        .owner_references()
+        .iter()
+        .find(|r| r.kind == "Pod" && r.api_version == "v1");
+
+    if let Some(owner) = vmi.labels().get(OWNER_POD_LABEL) {
+        let uid = owner_ref
+            .filter(|r| &r.name == owner)
+            .map(|r| r.uid.clone());
+        return (owner.clone(), uid);
+    }
+
+    match owner_ref {
+        Some(r) => (r.name.clone(), Some(r.uid.clone())),
+        None => (vmi.name_any(), None),
+    }
+}
@@ -0,0 +1,140 @@
+//! Checking runner registration with the GitHub API.
+
+use anyhow::{anyhow, Context, Result as AnyResult};
+use hyper::{body, client::HttpConnector, header, Body, Client, Request};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use serde::Deserialize;
+
+/// The API of github.com.
+const GITHUB_API_URL: &str = "https://api.github.com";
+
+/// Looks up self-hosted runners with the GitHub API.
+pub struct RunnerRegistry {
+    client: Client<HttpsConnector<HttpConnector>>,
+    endpoint: String,
+    token: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct RunnerList {
+    runners: Vec<Runner>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Runner {
+    name: String,
+    status: String,
+}
+
+impl RunnerRegistry {
+    /// Creates a registry for the runners of an organization, repo or enterprise.
+    ///
+    /// `runner_url` is the URL the runner registers to, and `api_url`
+    /// overrides the API URL that is derived from it.
+    pub fn new(runner_url: &str, api_url: Option<&str>, token: String) -> AnyResult<Self> {
+        let connector = HttpsConnectorBuilder::new()
+            .with_native_roots()
+            .https_or_http()
+            .enable_http1()
+            .build();
+
+        Ok(Self {
+            client: Client::builder().build(connector),
+            endpoint: runners_endpoint(runner_url, api_url)?,
+            token,
+        })
+    }
+
+    /// Returns whether a runner with the name is online.
+    pub async fn is_online(&self, name: &str) -> AnyResult<bool> {
+        let query = form_urlencoded::Serializer::new(String::new())
+            .append_pair("name", name)
+            .append_pair("per_page", "100")
+            .finish();
+        let request = Request::get(format!("{}?{}", self.endpoint, query))
+            .header(header::ACCEPT, "application/vnd.github+json")
+            .header(header::AUTHORIZATION, format!("Bearer {}", self.token))
+            .header(header::USER_AGENT, "kubevirt-actions-runner")
+            .body(Body::empty())?;
+        let response = self.client.request(request).await?;
+
+        let status = response.status();
+        if !status.is_success() {
+            return Err(anyhow!("GitHub API returned {}", status));
+        }
+
+        let body = body::to_bytes(response.into_body()).await?;
+        let list: RunnerList =
+            serde_json::from_slice(&body).context("Failed to parse the list of runners")?;
+
+        Ok(list
+            .runners
+            .iter()
+            .any(|runner| runner.name == name && runner.status == "online"))
+    }
+}
+
+/// Returns the API endpoint listing the runners registered to a URL.
+///
+/// For GitHub Enterprise Server, the API is assumed to be at `/api/v3`
+/// unless `api_url` is specified.
+pub fn runners_endpoint(runner_url: &str, api_url: Option<&str>) -> AnyResult<String> {
+    let invalid = || anyhow!("Cannot determine the runners API for {}", runner_url);
+
+    let (scheme, rest) = runner_url.split_once("://").ok_or_else(invalid)?;
+    let (host, path) = rest.split_once('/').unwrap_or((rest, ""));
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+
+    let api_url = match api_url {
+        Some(api_url) => api_url.trim_end_matches('/').to_string(),
+        None if host == "github.com" => GITHUB_API_URL.to_string(),
+        None => format!("{}://{}/api/v3", scheme, host),
+    };
+
+    let path = match segments[..] {
+        ["enterprises", enterprise] => format!("enterprises/{}", enterprise),
+        [org] => format!("orgs/{}", org),
+        [owner, repo] => format!("repos/{}/{}", owner, repo),
+        _ => return Err(invalid()),
+    };
+
+    Ok(format!("{}/{}/actions/runners", api_url, path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_runners_endpoint() {
+        let cases = [
+            (
+                "https://github.com/org",
+                None,
+                "https://api.github.com/orgs/org/actions/runners",
+            ),
+            (
+                "https://github.com/org/repo/",
+                None,
+                "https://api.github.com/repos/org/repo/actions/runners",
+            ),
+            (
+                "https://ghe.example.com/enterprises/ent",
+                None,
+                "https://ghe.example.com/api/v3/enterprises/ent/actions/runners",
+            ),
+            (
+                "https://ghe.example.com/org",
+                Some("https://api.ghe.example.com/"),
+                "https://api.ghe.example.com/orgs/org/actions/runners",
+            ),
+        ];
+
+        for (url, api_url, expected) in cases {
+            assert_eq!(expected, runners_endpoint(url, api_url).unwrap());
+        }
+
+        assert!(runners_endpoint("https://github.com/", None).is_err());
+        assert!(runners_endpoint("https://github.com/a/b/c", None).is_err());
+    }
+}
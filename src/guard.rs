@@ -0,0 +1,93 @@
+//! Cleaning up the VMI on panic.
+
+use std::thread;
+
+use anyhow::Result as AnyResult;
+use kube::{
+    api::{Api, DeleteParams, DynamicObject},
+    core::ApiResource,
+    Client,
+};
+
+/// Deletes the VMI if we panic before cleaning it up.
+///
+/// Normal returns and errors are handled by `run`, so this only
+/// does something while unwinding. Since we can't run async code
+/// in `Drop`, the VMI is deleted with a blocking call on a
+/// dedicated runtime in a separate thread.
+///
+/// A guard for a VM created by us also deletes it on early returns,
+/// since KubeVirt would keep the VM running otherwise. It must be
+/// disarmed once `run` has taken care of the VM.
+pub struct VmiGuard {
+    namespace: String,
+    resource: ApiResource,
+    name: String,
+    on_return: bool,
+}
+
+impl VmiGuard {
+    /// Creates a guard for a created VMI.
+    pub fn new(namespace: &str, resource: &ApiResource, name: &str) -> Self {
+        Self {
+            namespace: namespace.to_string(),
+            resource: resource.clone(),
+            name: name.to_string(),
+            on_return: false,
+        }
+    }
+
+    /// Creates a guard for a created VM.
+    pub fn for_vm(namespace: &str, resource: &ApiResource, name: &str) -> Self {
+        let mut guard = Self::new(namespace, resource, name);
+        guard.on_return = true;
+        guard
+    }
+
+    /// Stops deleting the object on early returns.
+    pub fn disarm(&mut self) {
+        self.on_return = false;
+    }
+}
+
+impl Drop for VmiGuard {
+    fn drop(&mut self) {
+        let kind = &self.resource.kind;
+        if thread::panicking() {
+            tracing::warn!("Panicked - Deleting {} {}", kind, self.name);
+        } else if self.on_return {
+            tracing::warn!("Exiting early - Deleting {} {}", kind, self.name);
+        } else {
+            return;
+        }
+
+        let namespace = self.namespace.clone();
+        let resource = self.resource.clone();
+        let name = self.name.clone();
+        let result = thread::spawn(move || delete_blocking(&namespace, &resource, &name)).join();
+
+        match result {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => tracing::error!("Failed to delete {} {}: {}", kind, self.name, e),
+            Err(_) => tracing::error!("Failed to delete {} {}: Panicked", kind, self.name),
+        }
+    }
+}
+
+fn delete_blocking(namespace: &str, resource: &ApiResource, name: &str) -> AnyResult<()> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+
+    runtime.block_on(async {
+        // The original client may be bound to the runtime we are unwinding
+        let client = Client::try_default().await?;
+        let api: Api<DynamicObject> = Api::namespaced_with(client, namespace, resource);
+
+        match api.delete(name, &DeleteParams::default()).await {
+            Ok(_) => Ok(()),
+            Err(kube::Error::Api(e)) if e.code == 404 => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    })
+}
@@ -0,0 +1,89 @@
+//! Health check endpoints.
+
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use tokio::sync::{oneshot, watch};
+use tokio::task::JoinHandle;
+
+use crate::VmiState;
+
+/// A running health check server.
+pub struct HealthServer {
+    shutdown: oneshot::Sender<()>,
+    handle: JoinHandle<()>,
+}
+
+impl HealthServer {
+    /// Starts serving `/healthz` and `/readyz`.
+    ///
+    /// `/healthz` succeeds while we are proceeding normally, while
+    /// `/readyz` only succeeds when the VMI is ready. We are stuck if
+    /// we haven't heard about the VMI for longer than `stall_timeout`.
+    pub fn start(
+        addr: SocketAddr,
+        state: watch::Receiver<VmiState>,
+        stall_timeout: Duration,
+    ) -> Option<Self> {
+        let builder = match Server::try_bind(&addr) {
+            Ok(builder) => builder,
+            Err(e) => {
+                tracing::warn!("Failed to bind health server to {}: {}", addr, e);
+                return None;
+            }
+        };
+
+        let make_service = make_service_fn(move |_| {
+            let state = state.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request| {
+                    let (healthy, ready) = {
+                        let state = state.borrow();
+                        (state.is_healthy(stall_timeout), state.ready)
+                    };
+                    async move { Ok::<_, Infallible>(handle_request(request, healthy, ready)) }
+                }))
+            }
+        });
+
+        let (shutdown, shutdown_rx) = oneshot::channel();
+        let server = builder.serve(make_service).with_graceful_shutdown(async {
+            shutdown_rx.await.ok();
+        });
+
+        tracing::info!("Serving health checks at {}", addr);
+
+        let handle = tokio::spawn(async move {
+            if let Err(e) = server.await {
+                tracing::warn!("Health server failed: {}", e);
+            }
+        });
+
+        Some(Self { shutdown, handle })
+    }
+
+    /// Shuts down the server.
+    pub async fn shutdown(self) {
+        self.shutdown.send(()).ok();
+        self.handle.await.ok();
+    }
+}
+
+fn handle_request(request: Request<Body>, healthy: bool, ready: bool) -> Response<Body> {
+    let status = match (request.method(), request.uri().path()) {
+        (&Method::GET, "/healthz") if healthy => StatusCode::OK,
+        (&Method::GET, "/healthz") => StatusCode::INTERNAL_SERVER_ERROR,
+        (&Method::GET, "/readyz") if ready => StatusCode::OK,
+        (&Method::GET, "/readyz") => StatusCode::SERVICE_UNAVAILABLE,
+        _ => StatusCode::NOT_FOUND,
+    };
+
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status;
+    response
+}
@@ -0,0 +1,1149 @@
+//! Launching runner VMIs.
+
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context, Result as AnyResult};
+use k8s_openapi::api::core::v1::Event;
+use kube::{
+    api::Api,
+    runtime::{events::EventType, wait::await_condition},
+    Client, Resource, ResourceExt,
+};
+use tokio::sync::watch;
+use tracing::Instrument;
+
+use crate::{
+    console::{self, ConsoleTail},
+    diagnostics, discover_kubevirt,
+    error::RunnerError,
+    events::EventPublisher,
+    github::RunnerRegistry,
+    lifecycle::{
+        adopt_or_delete_existing_vmi, cleanup, create, delete_orphaned_vmis, keep_vmi, vm_for_vmi,
+        Apis, Created, Origin,
+    },
+    metrics::{Metrics, MetricsServer},
+    opts::{is_dns_subdomain, Opts, StartMode, VmiNameStrategy},
+    resolve_namespace,
+    runner_info::{build_runner_info, runner_url, RunnerInfo},
+    template::{prepare, Plan},
+    vmi_name,
+    watch::{is_vmi_running, log_launcher_diagnostics, watch_runner, VmiState},
+    write_json_file, Summary, VmiOutcome,
+};
+
+/// Why a [`Launcher`] is asked to stop.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopRequest {
+    /// The runner pod is being terminated.
+    ///
+    /// An ephemeral runner that is already running is stopped
+    /// gracefully. Otherwise, this is like `Interrupt`.
+    Terminate,
+
+    /// Stop watching the VMI and clean it up.
+    Interrupt,
+}
+
+/// Asks a running [`Launcher`] to stop.
+///
+/// Clones share the same state, and only the first request counts.
+/// The command line interface requests a stop on SIGTERM, SIGINT,
+/// SIGHUP and SIGQUIT.
+#[derive(Debug, Clone)]
+pub struct StopHandle {
+    tx: Arc<watch::Sender<Option<StopRequest>>>,
+}
+
+impl StopHandle {
+    pub fn new() -> Self {
+        Self {
+            tx: Arc::new(watch::channel(None).0),
+        }
+    }
+
+    /// Asks the launcher to stop.
+    pub fn request(&self, request: StopRequest) {
+        self.tx.send_if_modified(|current| {
+            if current.is_some() {
+                return false;
+            }
+
+            *current = Some(request);
+            true
+        });
+    }
+
+    /// Waits until a stop is requested.
+    pub async fn requested(&self) -> StopRequest {
+        let mut rx = self.tx.subscribe();
+        loop {
+            if let Some(request) = *rx.borrow_and_update() {
+                return request;
+            }
+
+            // We hold the sender, so this can't fail
+            rx.changed().await.ok();
+        }
+    }
+}
+
+impl Default for StopHandle {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Launches a runner VMI and watches it, for embedding in other programs.
+///
+/// This is what the command line interface does, without the
+/// process-level concerns like logging, lingering and exit codes.
+/// Create one with [`Launcher::builder`].
+#[derive(Clone)]
+pub struct Launcher {
+    opts: Opts,
+    client: Option<Client>,
+    state: Arc<watch::Sender<VmiState>>,
+}
+
+impl Launcher {
+    /// Returns a builder with the default options.
+    pub fn builder() -> LauncherBuilder {
+        LauncherBuilder::default()
+    }
+
+    /// Returns the runner info that would be passed to the VM.
+    pub fn runner_info(&self) -> AnyResult<RunnerInfo> {
+        build_runner_info(&self.opts, &self.opts.env)
+    }
+
+    /// Returns what we know about the VMI of the current attempt.
+    ///
+    /// The last state is kept after [`Launcher::run`] returns. Clones
+    /// share the same state.
+    pub fn state(&self) -> watch::Receiver<VmiState> {
+        self.state.subscribe()
+    }
+
+    /// Creates the VMI, watches it until it's done, and cleans it up.
+    ///
+    /// `summary` is filled in as we go, so it's useful even on errors.
+    /// Requests on `stop` make us clean up and return early. No signal
+    /// handlers are installed.
+    pub async fn run(&self, summary: &mut Summary, stop: &StopHandle) -> Result<(), RunnerError> {
+        let client = match &self.client {
+            Some(client) => Ok(client.clone()),
+            None => Client::try_default().await.map_err(Into::into),
+        };
+        let result = match client {
+            Ok(client) => {
+                run_with_fallback(&client, self.opts.clone(), summary, stop, &self.state).await
+            }
+            Err(e) => Err(e),
+        };
+
+        // Run once after all attempts, including when we failed early
+        let Some(command) = &self.opts.on_complete else {
+            return result;
+        };
+        let hook_result = run_on_complete(command, summary).await;
+        if let Err(e) = &hook_result {
+            tracing::warn!("{:#}", e);
+        }
+
+        if result.is_ok() && self.opts.on_complete_strict {
+            hook_result?;
+        }
+        result
+    }
+}
+
+/// Builds a [`Launcher`].
+///
+/// Options without a setter can be set with [`LauncherBuilder::opts`].
+#[derive(Clone, Default)]
+pub struct LauncherBuilder {
+    opts: Opts,
+    client: Option<Client>,
+}
+
+impl LauncherBuilder {
+    /// Replaces all options.
+    pub fn opts(mut self, opts: Opts) -> Self {
+        self.opts = opts;
+        self
+    }
+
+    /// Sets the Kubernetes client.
+    ///
+    /// By default, the client is inferred from the environment.
+    pub fn client(mut self, client: Client) -> Self {
+        self.client = Some(client);
+        self
+    }
+
+    /// Sets the name of the VM template, or a comma-separated list to fall back through.
+    pub fn vm_template(mut self, vm_template: impl Into<String>) -> Self {
+        self.opts.vm_template = Some(vm_template.into());
+        self
+    }
+
+    /// Sets the name of the runner.
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.opts.name = name.into();
+        self
+    }
+
+    /// Sets the namespace to create the VMI in.
+    pub fn namespace(mut self, namespace: impl Into<String>) -> Self {
+        self.opts.namespace = Some(namespace.into());
+        self
+    }
+
+    /// Sets the URL of the repository, organization or enterprise to register with.
+    pub fn url(mut self, url: impl Into<String>) -> Self {
+        self.opts.url = Some(url.into());
+        self
+    }
+
+    /// Sets the registration token.
+    pub fn token(mut self, token: impl Into<String>) -> Self {
+        self.opts.token = Some(token.into());
+        self
+    }
+
+    /// Sets the encoded JIT config, which is used instead of a token.
+    pub fn jitconfig(mut self, jitconfig: impl Into<String>) -> Self {
+        self.opts.jitconfig = Some(jitconfig.into());
+        self
+    }
+
+    /// Sets the comma-separated labels of the runner.
+    pub fn labels(mut self, labels: impl Into<String>) -> Self {
+        self.opts.labels = labels.into();
+        self
+    }
+
+    /// Sets the comma-separated groups of the runner.
+    pub fn groups(mut self, groups: impl Into<String>) -> Self {
+        self.opts.groups = groups.into();
+        self
+    }
+
+    /// Sets whether the runner takes a single job.
+    pub fn ephemeral(mut self, ephemeral: bool) -> Self {
+        self.opts.ephemeral = ephemeral;
+        self
+    }
+
+    /// Sets a variable that would otherwise come from the environment.
+    pub fn env(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.opts.env.insert(key.into(), value.into());
+        self
+    }
+
+    /// Checks the options and creates the launcher.
+    pub fn build(mut self) -> Result<Launcher, RunnerError> {
+        self.opts.resolve_name().map_err(RunnerError::Config)?;
+        self.opts.validate().map_err(RunnerError::Config)?;
+
+        Ok(Launcher {
+            opts: self.opts,
+            client: self.client,
+            state: Arc::new(watch::channel(VmiState::default()).0),
+        })
+    }
+}
+
+/// Runs with each of the VM templates in turn until a VMI is scheduled.
+async fn run_with_fallback(
+    client: &Client,
+    opts: Opts,
+    summary: &mut Summary,
+    stop: &StopHandle,
+    state: &watch::Sender<VmiState>,
+) -> Result<(), RunnerError> {
+    let templates: Vec<String> = match &opts.vm_template {
+        Some(templates) => templates
+            .split(',')
+            .map(str::trim)
+            .filter(|t| !t.is_empty())
+            .map(str::to_string)
+            .collect(),
+        None => Vec::new(),
+    };
+
+    let (last, candidates) = match templates.split_last() {
+        Some((last, candidates)) if !candidates.is_empty() => (last, candidates),
+        _ => return run_with_retries(client, opts, summary, stop, state).await,
+    };
+
+    for template in candidates {
+        let result = run_with_template(client, &opts, template, summary, stop, state).await;
+
+        let never_scheduled = result
+            .as_ref()
+            .is_err_and(|e| e.outcome().is_some_and(VmiOutcome::never_scheduled));
+        if !never_scheduled {
+            return result;
+        }
+
+        // The next VMI may get the same name
+        if opts.keep_vmi_on_failure {
+            tracing::warn!(
+                "VMI from template {} could not be scheduled - Not falling back since it was kept",
+                template
+            );
+            return result;
+        }
+
+        tracing::warn!(
+            "VMI from template {} could not be scheduled - Falling back to the next template",
+            template
+        );
+    }
+
+    run_with_template(client, &opts, last, summary, stop, state).await
+}
+
+/// Runs with one of the VM templates.
+async fn run_with_template(
+    client: &Client,
+    opts: &Opts,
+    template: &str,
+    summary: &mut Summary,
+    stop: &StopHandle,
+    state: &watch::Sender<VmiState>,
+) -> Result<(), RunnerError> {
+    tracing::info!("Trying VM template {}", template);
+
+    let mut opts = opts.clone();
+    opts.vm_template = Some(template.to_string());
+    run_with_retries(client, opts, summary, stop, state).await
+}
+
+/// Runs, recreating the VMI if it fails before reaching `Running`.
+async fn run_with_retries(
+    client: &Client,
+    opts: Opts,
+    summary: &mut Summary,
+    stop: &StopHandle,
+    state: &watch::Sender<VmiState>,
+) -> Result<(), RunnerError> {
+    let mut retries = 0;
+    loop {
+        *summary = Summary::default();
+        let result = run(client, opts.clone(), summary, stop, state).await;
+
+        let failed_to_boot = result
+            .as_ref()
+            .is_err_and(|e| e.outcome().is_some_and(VmiOutcome::failed_to_boot));
+        if !failed_to_boot || retries >= opts.boot_retries {
+            return result;
+        }
+
+        retries += 1;
+        tracing::warn!(
+            "VMI failed before reaching Running - Recreating it ({}/{})",
+            retries,
+            opts.boot_retries
+        );
+    }
+}
+
+async fn run(
+    client: &Client,
+    mut opts: Opts,
+    summary: &mut Summary,
+    stop: &StopHandle,
+    state: &watch::Sender<VmiState>,
+) -> Result<(), RunnerError> {
+    state.send_replace(VmiState::default());
+
+    let env = opts.env.clone();
+    let vm_mode = opts.start_mode == StartMode::Vm;
+    let vmi_name = if vm_mode {
+        // KubeVirt names the VMI after the VM
+        opts.vm_template
+            .clone()
+            .ok_or_else(|| RunnerError::Config(anyhow!("--start-mode vm requires --vm-template")))?
+    } else {
+        vmi_name(
+            opts.vmi_name.as_deref().unwrap_or(&opts.name),
+            opts.vmi_name_strategy,
+            env.get("GITHUB_RUN_ID").map(String::as_str),
+            opts.pod_uid.as_deref(),
+        )
+        .map_err(RunnerError::Config)?
+    };
+    // The name also ends up in field selectors
+    if !is_dns_subdomain(&vmi_name) {
+        return Err(RunnerError::Config(anyhow!(
+            "Invalid VMI name {:?} - It must consist of lowercase alphanumeric characters, '-' and '.', \
+            and start and end with an alphanumeric character",
+            vmi_name
+        )));
+    }
+    if vmi_name != opts.name {
+        tracing::Span::current().record("vmi_name", &vmi_name);
+    }
+
+    let registry = match (&opts.github_pat, &opts.jitconfig) {
+        // The name is in the encoded JIT config and may differ from ours
+        (Some(_), Some(_)) => {
+            tracing::warn!("Registration is not checked for JIT runners");
+            None
+        }
+        (Some(pat), None) => {
+            let runner_url = runner_url(
+                opts.url.as_deref(),
+                opts.runner_scope,
+                opts.runner_target.as_deref(),
+                &env,
+            )
+            .map_err(RunnerError::Config)?;
+            Some(RunnerRegistry::new(
+                &runner_url,
+                opts.github_api_url.as_deref(),
+                pat.clone(),
+            )?)
+        }
+        (None, _) => None,
+    };
+    let runner_info = build_runner_info(&opts, &env).map_err(RunnerError::Config)?;
+    if let RunnerInfo::Legacy(info) = &runner_info {
+        // Spaces, empty and duplicate entries are dropped rather than rejected
+        if info.groups != opts.groups {
+            tracing::warn!("Normalized runner groups to: {}", info.groups);
+        }
+        if info.labels != opts.labels {
+            tracing::warn!("Normalized runner labels to: {}", info.labels);
+        }
+        tracing::info!("Runner URL: {}", info.url);
+    }
+
+    let namespace = &resolve_namespace(opts.namespace.as_deref(), &opts.env, client);
+    tracing::Span::current().record("namespace", namespace);
+    summary.namespace = Some(namespace.clone());
+
+    let (vm_resource, vmi_resource) = discover_kubevirt(client, opts.setup_retries).await?;
+
+    let template_namespace = opts.vm_template_namespace.as_deref().unwrap_or(namespace);
+    if vm_mode && template_namespace != namespace {
+        return Err(RunnerError::Config(anyhow!(
+            "With --start-mode vm, the VM must be in the namespace we operate in"
+        )));
+    }
+    let apis = Apis::new(
+        client.clone(),
+        namespace,
+        template_namespace,
+        vm_resource,
+        vmi_resource,
+    );
+
+    let generate_name = !vm_mode && opts.vmi_name_strategy == VmiNameStrategy::Generate;
+
+    let mut existing = None;
+    if !opts.dry_run {
+        // The VMI of a VM would just be recreated
+        if !generate_name && opts.start_mode == StartMode::Ephemeral {
+            existing =
+                adopt_or_delete_existing_vmi(&apis.vmis, &apis.pods, &vmi_name, &opts).await?;
+        }
+
+        if let Some(selector) = &opts.gc_label_selector {
+            delete_orphaned_vmis(&apis.vmis, &apis.pods, selector).await?;
+        }
+    }
+
+    let plan = prepare(
+        &apis,
+        &mut opts,
+        runner_info,
+        &vmi_name,
+        generate_name,
+        stop,
+    )
+    .await?;
+    if opts.dry_run {
+        return print_plan(&apis, &opts, plan).map_err(Into::into);
+    }
+
+    let metrics = Arc::new(Metrics::new());
+    let metrics_server = opts
+        .metrics_addr
+        .and_then(|addr| MetricsServer::start(addr, metrics.clone()));
+
+    let mut created = create(&apis, &opts, plan, existing).await?;
+    let vmi = &created.vmi;
+    let vmi_name = vmi.name_any();
+    let vmi_uid = vmi.uid().unwrap_or_default();
+    tracing::Span::current().record("vmi_uid", &vmi_uid);
+    summary.vmi_name = Some(vmi_name.clone());
+    summary.vmi_uid = Some(vmi_uid.clone());
+
+    let status_writer = if let Some(status_file) = &opts.status_file {
+        let mut status = serde_json::json!({
+            "name": vmi.metadata.name,
+            "namespace": vmi.metadata.namespace,
+            "uid": vmi.metadata.uid,
+            "creationTimestamp": vmi.metadata.creation_timestamp,
+        });
+
+        if let Err(e) = write_json_file(status_file, &status) {
+            tracing::warn!(
+                "Failed to write status file {}: {}",
+                status_file.display(),
+                e
+            );
+        }
+
+        // Add the guest IP once it's known
+        let status_file = status_file.clone();
+        let mut state = state.subscribe();
+        Some(tokio::spawn(async move {
+            while state.changed().await.is_ok() {
+                let Some(guest_ip) = state.borrow_and_update().guest_ip.clone() else {
+                    continue;
+                };
+
+                status["guestIP"] = guest_ip.into();
+                if let Err(e) = write_json_file(&status_file, &status) {
+                    tracing::warn!(
+                        "Failed to write status file {}: {}",
+                        status_file.display(),
+                        e
+                    );
+                }
+            }
+        }))
+    } else {
+        None
+    };
+
+    let events = if opts.no_events {
+        EventPublisher::disabled()
+    } else {
+        EventPublisher::new(
+            apis.client.clone(),
+            opts.pod_name.as_deref(),
+            opts.pod_uid.as_deref(),
+            vmi.object_ref(&apis.vmi_resource),
+        )
+    };
+
+    if created.origin != Origin::Created {
+        events
+            .publish(
+                EventType::Normal,
+                "VMIAdopted",
+                format!("Adopted existing VMI {}", vmi_name),
+            )
+            .await;
+    } else {
+        events
+            .publish(
+                EventType::Normal,
+                "VMICreated",
+                format!("Created VMI {}", vmi_name),
+            )
+            .await;
+    }
+
+    let console_tail = (opts.stream_console || opts.console_tail.is_some())
+        .then(|| ConsoleTail::new(opts.console_tail.unwrap_or(console::DEFAULT_TAIL_LINES)));
+    let console = if let Some(tail) = &console_tail {
+        let vmis = apis.vmis.clone();
+        let client = apis.client.clone();
+        let namespace = namespace.to_string();
+        let vmi_name = vmi_name.clone();
+        let tail = tail.clone();
+        let relay = opts.stream_console;
+
+        Some(tokio::spawn(
+            async move {
+                if let Err(e) = await_condition(vmis, &vmi_name, is_vmi_running).await {
+                    tracing::warn!("Failed to wait for the VMI to run: {}", e);
+                    return;
+                }
+
+                if let Err(e) =
+                    console::stream_console(client, &namespace, &vmi_name, &tail, relay).await
+                {
+                    tracing::warn!("Failed to read the serial console: {}", e);
+                }
+            }
+            .in_current_span(),
+        ))
+    } else {
+        None
+    };
+
+    let outcome = watch_runner(
+        &apis,
+        &opts,
+        &created,
+        registry.as_ref(),
+        &events,
+        &metrics,
+        state,
+        stop,
+    )
+    .await;
+
+    if let Some(console) = console {
+        console.abort();
+    }
+
+    if let Some(status_writer) = status_writer {
+        status_writer.abort();
+    }
+
+    let outcome = match outcome {
+        Ok(outcome) => outcome,
+        Err(e) => {
+            // Nobody would be left to clean up the VMI
+            tracing::warn!("Failed to watch the VMI - Cleaning up");
+            let outcome = VmiOutcome::WatchInterrupted;
+            if let Err(e) = cleanup(&apis, &opts, &mut created, &outcome).await {
+                tracing::warn!("{:#}", e);
+            }
+            return Err(e);
+        }
+    };
+
+    metrics.record_outcome(outcome.as_str(), created.created.elapsed());
+
+    summary.outcome = Some(outcome.as_str().to_string());
+    {
+        let state = state.borrow();
+        summary.phase = state.phase.clone();
+        summary.startup_seconds = state.startup.map(|startup| startup.as_secs_f64());
+    }
+
+    report_outcome(
+        &apis,
+        &opts,
+        &created,
+        &outcome,
+        &events,
+        console_tail.as_ref(),
+    )
+    .await;
+
+    let cleaned_up = cleanup(&apis, &opts, &mut created, &outcome).await;
+
+    if let Some(pushgateway) = &opts.metrics_pushgateway {
+        if let Err(e) = metrics.push(pushgateway, &vmi_name).await {
+            tracing::warn!("Failed to push metrics: {}", e);
+        }
+    }
+
+    if let Some(metrics_server) = metrics_server {
+        metrics_server.shutdown().await;
+    }
+
+    cleaned_up?;
+
+    if let VmiOutcome::StartupTimedOut { last_phase } = &outcome {
+        let message = format!(
+            "VMI did not reach Running within {:?} (last phase: {})",
+            opts.startup_timeout, last_phase
+        );
+        return Err(RunnerError::Outcome { outcome, message });
+    }
+
+    // Keeping the VMI doesn't change the exit code
+    if outcome.is_abnormal() {
+        let mut message = format!("VMI outcome: {:?}", outcome);
+        if keep_vmi(&opts, &outcome) {
+            message.push_str(" (VMI was kept)");
+        }
+        return Err(RunnerError::Outcome { outcome, message });
+    }
+
+    Ok(())
+}
+
+/// Prints the VMI, or the VM for it, and its Secrets instead of creating them.
+fn print_plan(apis: &Apis, opts: &Opts, plan: Plan) -> AnyResult<()> {
+    let mut vmi = plan.vmi;
+    vmi.metadata.namespace = Some(apis.namespace.clone());
+    if opts.start_mode == StartMode::CreateVm {
+        print!(
+            "{}",
+            serde_yaml::to_string(&vm_for_vmi(&vmi, &apis.vm_resource))
+                .context("Failed to serialize the VM")?
+        );
+    } else {
+        print!(
+            "{}",
+            serde_yaml::to_string(&vmi).context("Failed to serialize the VMI")?
+        );
+    }
+
+    for secret in &plan.secrets {
+        print!(
+            "---\n{}",
+            serde_yaml::to_string(secret).context("Failed to serialize the Secret")?
+        );
+    }
+
+    Ok(())
+}
+
+/// Publishes events and collects diagnostics for the outcome.
+async fn report_outcome(
+    apis: &Apis,
+    opts: &Opts,
+    created: &Created,
+    outcome: &VmiOutcome,
+    events: &EventPublisher,
+    console_tail: Option<&ConsoleTail>,
+) {
+    let vmi_name = created.vmi.name_any();
+
+    if matches!(
+        outcome,
+        VmiOutcome::Failed { .. } | VmiOutcome::StartupTimedOut { .. }
+    ) {
+        if let Some(vmi_uid) = &created.vmi.metadata.uid {
+            log_launcher_diagnostics(&apis.pods, vmi_uid).await;
+        }
+    }
+
+    match outcome {
+        VmiOutcome::Deleted => {
+            events
+                .publish(
+                    EventType::Warning,
+                    "VMIDeleted",
+                    format!("VMI {} was deleted by something", vmi_name),
+                )
+                .await;
+        }
+        VmiOutcome::WatchInterrupted => {
+            events
+                .publish(
+                    EventType::Normal,
+                    "WatchInterrupted",
+                    format!("Stopped watching VMI {}", vmi_name),
+                )
+                .await;
+        }
+        VmiOutcome::Stopped => {
+            events
+                .publish(
+                    EventType::Normal,
+                    "VMIStopped",
+                    format!("Stopping VMI {} as requested", vmi_name),
+                )
+                .await;
+        }
+        VmiOutcome::MaxRuntimeExceeded => {
+            events
+                .publish(
+                    EventType::Warning,
+                    "VMIMaxRuntimeExceeded",
+                    format!(
+                        "VMI {} was running for longer than {:?}",
+                        vmi_name,
+                        opts.max_runtime.unwrap_or_default()
+                    ),
+                )
+                .await;
+        }
+        VmiOutcome::RegistrationTimedOut => {
+            events
+                .publish(
+                    EventType::Warning,
+                    "RunnerRegistrationTimedOut",
+                    format!(
+                        "Runner {} did not register within {:?}",
+                        opts.name, opts.registration_timeout
+                    ),
+                )
+                .await;
+        }
+        VmiOutcome::StartupTimedOut { last_phase } => {
+            events
+                .publish(
+                    EventType::Warning,
+                    "VMIStartupTimedOut",
+                    format!(
+                        "VMI {} did not reach Running within {:?} (last phase: {})",
+                        vmi_name, opts.startup_timeout, last_phase
+                    ),
+                )
+                .await;
+        }
+        // Already published as phase transitions
+        VmiOutcome::Succeeded | VmiOutcome::Failed { .. } => {}
+    }
+
+    // With `--stream-console`, the lines have already been logged
+    let dump_console = matches!(outcome, VmiOutcome::Failed { .. }) && !opts.stream_console;
+    if let Some(tail) = console_tail.filter(|_| dump_console) {
+        let lines = tail.lines();
+        if lines.is_empty() {
+            tracing::info!("Nothing was captured from the serial console");
+        } else {
+            tracing::info!("Last {} lines of the serial console:", lines.len());
+            for line in lines {
+                tracing::info!(target: "console", "{}", line);
+            }
+        }
+    }
+
+    if let Some(diagnostics_dir) = &opts.diagnostics_dir {
+        if matches!(
+            outcome,
+            VmiOutcome::Failed { .. }
+                | VmiOutcome::WatchInterrupted
+                | VmiOutcome::StartupTimedOut { .. }
+        ) {
+            let events: Api<Event> = Api::namespaced(apis.client.clone(), &apis.namespace);
+            if let Err(e) = diagnostics::capture(
+                diagnostics_dir,
+                &apis.vmis,
+                &apis.pods,
+                &events,
+                &vmi_name,
+                console_tail,
+            )
+            .await
+            {
+                tracing::warn!("Failed to capture diagnostics: {:#}", e);
+            }
+        }
+    }
+}
+
+/// Runs the on-complete command with information from the summary.
+///
+/// The outcome is `Error` if we failed before the VMI reached one.
+async fn run_on_complete(command: &str, summary: &Summary) -> AnyResult<()> {
+    tracing::info!("Running on-complete command");
+
+    let status = tokio::process::Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .env(
+            "KUBEVIRT_OUTCOME",
+            summary.outcome.as_deref().unwrap_or("Error"),
+        )
+        .env(
+            "KUBEVIRT_VMI_NAME",
+            summary.vmi_name.as_deref().unwrap_or_default(),
+        )
+        .env(
+            "KUBEVIRT_VMI_NAMESPACE",
+            summary.namespace.as_deref().unwrap_or_default(),
+        )
+        .status()
+        .await
+        .context("Failed to run the on-complete command")?;
+
+    if !status.success() {
+        return Err(anyhow!("The on-complete command failed with {}", status));
+    }
+
+    tracing::info!("On-complete command succeeded");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::fake::{FakeCluster, NAMESPACE, PODS, SECRETS, VMIS, VMS};
+    use crate::opts::RunnerInfoMode;
+    use crate::{MANAGED_BY, MANAGED_BY_LABEL, OWNER_POD_LABEL};
+
+    /// Returns a launcher running in the pod `runner-pod` against `cluster`.
+    fn fake_launcher(cluster: &FakeCluster, configure: impl FnOnce(&mut Opts)) -> Launcher {
+        cluster.insert(
+            VMS,
+            json!({
+                "apiVersion": "kubevirt.io/v1",
+                "kind": "VirtualMachine",
+                "metadata": { "name": "runner-template" },
+                "spec": {
+                    "template": {
+                        "metadata": {},
+                        "spec": { "domain": { "devices": {} } },
+                    },
+                },
+            }),
+        );
+
+        let mut opts = Opts {
+            vm_template: Some("runner-template".to_string()),
+            url: Some("https://github.com/org".to_string()),
+            token: Some("abc".to_string()),
+            ephemeral: true,
+            namespace: Some(NAMESPACE.to_string()),
+            pod_name: Some("runner-pod".to_string()),
+            pod_uid: Some("runner-pod-uid".to_string()),
+            ..Default::default()
+        };
+        configure(&mut opts);
+
+        Launcher::builder()
+            .opts(opts)
+            .client(cluster.client())
+            .build()
+            .unwrap()
+    }
+
+    /// Adds a VMI called `runner` left over by the runner pod `owner`.
+    fn insert_existing_vmi(cluster: &FakeCluster, owner: &str, age: Duration) -> String {
+        let created =
+            k8s_openapi::chrono::Utc::now() - k8s_openapi::chrono::Duration::from_std(age).unwrap();
+        let vmi = cluster.insert(
+            VMIS,
+            json!({
+                "apiVersion": "kubevirt.io/v1",
+                "kind": "VirtualMachineInstance",
+                "metadata": {
+                    "name": "runner",
+                    "creationTimestamp": created,
+                    "labels": {
+                        MANAGED_BY_LABEL: MANAGED_BY,
+                        OWNER_POD_LABEL: owner,
+                    },
+                },
+                "spec": { "domain": { "devices": {} } },
+                "status": { "phase": "Running" },
+            }),
+        );
+        vmi["metadata"]["uid"].as_str().unwrap().to_string()
+    }
+
+    /// Waits until we watch the VMI called `runner`.
+    async fn wait_for_watch(cluster: &FakeCluster) {
+        cluster
+            .wait_until(|cluster| cluster.is_watched(VMIS, "runner"))
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_run_succeeded() {
+        let cluster = FakeCluster::new();
+        let launcher = fake_launcher(&cluster, |opts| {
+            opts.runner_info_mode = RunnerInfoMode::Secret;
+        });
+        let stop = StopHandle::new();
+        let mut summary = Summary::default();
+
+        let (result, ()) = tokio::join!(launcher.run(&mut summary, &stop), async {
+            wait_for_watch(&cluster).await;
+            assert!(cluster.get(SECRETS, "runner-runner-info").is_some());
+            cluster.set_phase("runner", "Running");
+            cluster.set_phase("runner", "Succeeded");
+        });
+
+        result.unwrap();
+        assert_eq!(Some("Succeeded"), summary.outcome.as_deref());
+        assert!(cluster.get(VMIS, "runner").is_none());
+        assert!(cluster.get(SECRETS, "runner-runner-info").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_run_resumes_own_vmi() {
+        let cluster = FakeCluster::new();
+        let uid = insert_existing_vmi(&cluster, "runner-pod", Duration::from_secs(60));
+        let launcher = fake_launcher(&cluster, |_| {});
+        let stop = StopHandle::new();
+        let mut summary = Summary::default();
+
+        let (result, ()) = tokio::join!(launcher.run(&mut summary, &stop), async {
+            wait_for_watch(&cluster).await;
+            cluster.set_phase("runner", "Succeeded");
+        });
+
+        // It's ours, so it's cleaned up
+        result.unwrap();
+        assert_eq!(Some(uid), summary.vmi_uid);
+        assert!(cluster.get(VMIS, "runner").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_run_leaves_adopted_vmi() {
+        let cluster = FakeCluster::new();
+        cluster.insert(
+            PODS,
+            json!({
+                "apiVersion": "v1",
+                "kind": "Pod",
+                "metadata": { "name": "other-pod" },
+                "status": { "phase": "Running" },
+            }),
+        );
+        let uid = insert_existing_vmi(&cluster, "other-pod", Duration::from_secs(60));
+        let launcher = fake_launcher(&cluster, |_| {});
+        let stop = StopHandle::new();
+        let mut summary = Summary::default();
+
+        let (result, ()) = tokio::join!(launcher.run(&mut summary, &stop), async {
+            wait_for_watch(&cluster).await;
+            cluster.set_phase("runner", "Succeeded");
+        });
+
+        // The other runner pod takes care of it
+        result.unwrap();
+        assert_eq!(Some(uid), summary.vmi_uid);
+        assert!(cluster.get(VMIS, "runner").is_some());
+    }
+
+    #[tokio::test]
+    async fn test_run_reclaims_old_vmi() {
+        let cluster = FakeCluster::new();
+        cluster.insert(
+            PODS,
+            json!({
+                "apiVersion": "v1",
+                "kind": "Pod",
+                "metadata": { "name": "other-pod" },
+                "status": { "phase": "Running" },
+            }),
+        );
+        let uid = insert_existing_vmi(&cluster, "other-pod", Duration::from_secs(2 * 24 * 60 * 60));
+        let launcher = fake_launcher(&cluster, |_| {});
+        let stop = StopHandle::new();
+        let mut summary = Summary::default();
+
+        let (result, ()) = tokio::join!(launcher.run(&mut summary, &stop), async {
+            wait_for_watch(&cluster).await;
+            cluster.set_phase("runner", "Succeeded");
+        });
+
+        result.unwrap();
+        assert_ne!(Some(uid), summary.vmi_uid);
+        assert!(cluster.get(VMIS, "runner").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_run_cleans_up_after_watch_errors() {
+        let cluster = FakeCluster::new();
+        let launcher = fake_launcher(&cluster, |opts| {
+            opts.runner_info_mode = RunnerInfoMode::Secret;
+            opts.max_watch_errors = 0;
+        });
+        let stop = StopHandle::new();
+        let mut summary = Summary::default();
+
+        let (result, ()) = tokio::join!(launcher.run(&mut summary, &stop), async {
+            wait_for_watch(&cluster).await;
+            cluster.set_phase("runner", "Running");
+            cluster.fail_watches();
+        });
+
+        assert!(matches!(result, Err(RunnerError::Watch(_))));
+        assert!(cluster.get(VMIS, "runner").is_none());
+        assert!(cluster.get(SECRETS, "runner-runner-info").is_none());
+
+        // The health checks keep failing after we return
+        let state = launcher.state();
+        assert!(state.borrow().watch_failing);
+        assert!(!state.borrow().is_healthy(Duration::from_secs(600)));
+    }
+
+    #[tokio::test]
+    async fn test_run_interrupted() {
+        let cluster = FakeCluster::new();
+        let launcher = fake_launcher(&cluster, |_| {});
+        let stop = StopHandle::new();
+        let mut summary = Summary::default();
+
+        let (result, ()) = tokio::join!(launcher.run(&mut summary, &stop), async {
+            wait_for_watch(&cluster).await;
+            cluster.set_phase("runner", "Running");
+            stop.request(StopRequest::Interrupt);
+        });
+
+        let outcome = result.unwrap_err().outcome().cloned();
+        assert_eq!(Some(VmiOutcome::WatchInterrupted), outcome);
+        assert!(cluster.get(VMIS, "runner").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_run_terminated() {
+        // A running ephemeral runner is done
+        let cluster = FakeCluster::new();
+        let launcher = fake_launcher(&cluster, |_| {});
+        let stop = StopHandle::new();
+        let mut summary = Summary::default();
+
+        let (result, ()) = tokio::join!(launcher.run(&mut summary, &stop), async {
+            wait_for_watch(&cluster).await;
+            cluster.set_phase("runner", "Running");
+            stop.request(StopRequest::Terminate);
+        });
+
+        result.unwrap();
+        assert_eq!(Some("Stopped"), summary.outcome.as_deref());
+        assert!(cluster.get(VMIS, "runner").is_none());
+
+        // Otherwise, it's like an interruption
+        let cluster = FakeCluster::new();
+        let launcher = fake_launcher(&cluster, |_| {});
+        let stop = StopHandle::new();
+
+        let (result, ()) = tokio::join!(launcher.run(&mut summary, &stop), async {
+            wait_for_watch(&cluster).await;
+            stop.request(StopRequest::Terminate);
+        });
+
+        let outcome = result.unwrap_err().outcome().cloned();
+        assert_eq!(Some(VmiOutcome::WatchInterrupted), outcome);
+        assert!(cluster.get(VMIS, "runner").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_run_max_runtime() {
+        let cluster = FakeCluster::new();
+        let launcher = fake_launcher(&cluster, |opts| {
+            opts.max_runtime = Some(Duration::from_millis(100));
+        });
+        let stop = StopHandle::new();
+        let mut summary = Summary::default();
+
+        let (result, ()) = tokio::join!(launcher.run(&mut summary, &stop), async {
+            wait_for_watch(&cluster).await;
+            cluster.set_phase("runner", "Running");
+        });
+
+        let outcome = result.unwrap_err().outcome().cloned();
+        assert_eq!(Some(VmiOutcome::MaxRuntimeExceeded), outcome);
+        assert!(cluster.get(VMIS, "runner").is_none());
+    }
+
+    #[test]
+    fn test_launcher() {
+        let launcher = Launcher::builder()
+            .vm_template("runner")
+            .url("https://github.com/org")
+            .token("abc")
+            .labels("nixos, x64")
+            .ephemeral(true)
+            .build()
+            .unwrap();
+
+        let RunnerInfo::Legacy(info) = launcher.runner_info().unwrap() else {
+            panic!("Expected legacy runner info");
+        };
+        assert_eq!("https://github.com/org", info.url);
+        assert_eq!("nixos,x64", info.labels);
+        assert!(info.ephemeral);
+
+        let opts = Opts::default();
+        assert_eq!("runner", opts.name);
+        assert_eq!(Duration::from_secs(300), opts.startup_timeout);
+        assert!(opts.env.is_empty());
+    }
+}
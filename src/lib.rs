@@ -0,0 +1,859 @@
+//! Runs GitHub Actions runners in KubeVirt VMs.
+//!
+//! The command line interface is [`cli`]. To launch runner VMIs
+//! from your own program, use [`Launcher`].
+
+mod config;
+mod console;
+mod diagnostics;
+pub mod error;
+pub mod events;
+#[cfg(test)]
+mod fake;
+mod gc;
+mod github;
+mod guard;
+mod health;
+mod launcher;
+mod lifecycle;
+pub mod metrics;
+mod opts;
+mod runner_info;
+mod telemetry;
+mod template;
+mod watch;
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result as AnyResult};
+use k8s_openapi::api::core::v1::Pod;
+use kube::{
+    api::Api,
+    core::{ApiResource, NotUsed, Object, ObjectMeta},
+    discovery, Client,
+};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use tokio::time::Instant;
+use tracing::Instrument;
+
+use health::HealthServer;
+use lifecycle::is_transient_error;
+use runner_info::build_runner_info;
+
+pub use launcher::{Launcher, LauncherBuilder, StopHandle, StopRequest};
+pub use opts::{
+    Command, GcOpts, LogFormat, Mount, MountKind, Opts, PrintRunnerInfoOpts, RunnerInfoMode,
+    RunnerScope, StartMode, VmiNameStrategy,
+};
+pub use runner_info::{JitRunnerInfo, LegacyRunnerInfo, ProxyInfo, RunnerInfo};
+pub use watch::{wait_for_vmi, VmiState, WatchOptions};
+
+const RUNNER_INFO_ANNOTATION: &str = "li.zhaofeng.kubevirt-actions-runner/runner-info";
+const RUNNER_INFO_VOLUME: &str = "runner-info";
+const RUNNER_INFO_PATH: &str = "runner-info.json";
+const RUNNER_INFO_SECRET_KEY: &str = "runner-info";
+const SSH_KEYS_SECRET_SUFFIX: &str = "ssh-keys";
+const RUNNER_INPUT_PREFIX: &str = "ACTIONS_RUNNER_INPUT_";
+const CLOUD_INIT_SECRET_KEY: &str = "userdata";
+const CLOUD_INIT_VOLUME: &str = "cloudinit";
+const CLOUD_CONFIG_HEADER: &str = "#cloud-config";
+const SERVICE_ACCOUNT_NAMESPACE_FILE: &str =
+    "/var/run/secrets/kubernetes.io/serviceaccount/namespace";
+const DEFAULT_GITHUB_URL: &str = "https://github.com/";
+const DEFAULT_TOKEN_FILE: &str = "/var/run/secrets/kubevirt-actions-runner/token";
+const OWNER_POD_LABEL: &str = "li.zhaofeng.kubevirt-actions-runner/owner-pod";
+const MANAGED_BY_LABEL: &str = "app.kubernetes.io/managed-by";
+const MANAGED_BY: &str = "kubevirt-actions-runner";
+const DEFAULT_LABELS_ANNOTATION: &str = "li.zhaofeng.kubevirt-actions-runner/default-labels";
+const DEFAULT_GROUPS_ANNOTATION: &str = "li.zhaofeng.kubevirt-actions-runner/default-groups";
+const GITHUB_CONTEXT_ANNOTATION_PREFIX: &str = "li.zhaofeng.kubevirt-actions-runner/";
+
+/// Environment variables of the GitHub workflow context and the annotations they are stored in.
+const GITHUB_CONTEXT_VARS: &[(&str, &str)] = &[
+    ("GITHUB_REPOSITORY", "github-repository"),
+    ("GITHUB_RUN_ID", "github-run-id"),
+    ("GITHUB_WORKFLOW", "github-workflow"),
+    ("GITHUB_JOB", "github-job"),
+];
+const DEFAULT_ERROR_LINGER: Duration = Duration::from_secs(10);
+const REGISTRATION_POLL_INTERVAL: Duration = Duration::from_secs(10);
+const TEMPLATE_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+const IMAGE_PULL_POLL_INTERVAL: Duration = Duration::from_secs(30);
+const WATCH_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The total size of annotations Kubernetes accepts on an object.
+const ANNOTATIONS_SIZE_LIMIT: usize = 256 * 1024;
+
+pub type VirtualMachine = Object<VirtualMachineSpec, NotUsed>;
+pub type VirtualMachineInstance = Object<VirtualMachineInstanceSpec, VirtualMachineInstanceStatus>;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct VirtualMachineSpec {
+    pub template: VirtualMachineTemplate,
+
+    #[serde(rename = "runStrategy", skip_serializing_if = "Option::is_none")]
+    pub run_strategy: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct VirtualMachineTemplate {
+    pub metadata: ObjectMeta,
+    pub spec: VirtualMachineInstanceSpec,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct VirtualMachineInstanceSpec {
+    pub volumes: Option<Vec<Volume>>,
+
+    #[serde(flatten)]
+    pub data: BTreeMap<String, Value>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct VirtualMachineInstanceStatus {
+    pub phase: String,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub conditions: Vec<VirtualMachineInstanceCondition>,
+
+    #[serde(rename = "guestOSInfo", skip_serializing_if = "Option::is_none")]
+    pub guest_os_info: Option<GuestOsInfo>,
+
+    /// Why the VMI is in this state, e.g., `NodeUnresponsive`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+
+    /// The node the VMI is being evacuated from.
+    #[serde(rename = "evacuationNodeName", skip_serializing_if = "Option::is_none")]
+    pub evacuation_node_name: Option<String>,
+
+    /// The network interfaces of the guest.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub interfaces: Vec<VirtualMachineInstanceInterface>,
+}
+
+impl Default for VirtualMachineInstanceStatus {
+    fn default() -> Self {
+        Self {
+            phase: "Unknown".to_string(),
+            conditions: Vec::new(),
+            guest_os_info: None,
+            reason: None,
+            evacuation_node_name: None,
+            interfaces: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct VirtualMachineInstanceCondition {
+    #[serde(rename = "type")]
+    pub type_: String,
+    pub status: String,
+    pub reason: Option<String>,
+    pub message: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GuestOsInfo {
+    pub pretty_name: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VirtualMachineInstanceInterface {
+    pub ip_address: Option<String>,
+}
+
+/// A summary of the run, written to the summary file.
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Summary {
+    /// The name of the VMI.
+    pub vmi_name: Option<String>,
+
+    /// The UID of the VMI.
+    pub vmi_uid: Option<String>,
+
+    /// The namespace of the VMI.
+    pub namespace: Option<String>,
+
+    /// The last phase of the VMI we have seen.
+    pub phase: Option<String>,
+
+    /// The outcome, like `Succeeded` or `StartupTimedOut`.
+    pub outcome: Option<String>,
+
+    /// How long it took the VMI to reach `Running`, in seconds.
+    pub startup_seconds: Option<f64>,
+
+    /// How long the whole run took, in seconds.
+    pub duration_seconds: f64,
+
+    /// The error we exited with, if any.
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Volume {
+    pub name: String,
+
+    #[serde(flatten)]
+    pub data: BTreeMap<String, Value>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VmiOutcome {
+    /// The VMI has succeeded.
+    Succeeded,
+
+    /// The VMI has failed.
+    ///
+    /// The guest may have crashed, or the VMI may have been evicted or
+    /// lost its node. Both have their own exit code.
+    Failed {
+        /// Whether the guest or the infrastructure is at fault.
+        reason: FailureReason,
+
+        /// Whether the VMI had reached `Running` before it failed.
+        booted: bool,
+    },
+
+    /// The VMI was (force) deleted.
+    Deleted,
+
+    /// The watcher was interrupted.
+    WatchInterrupted,
+
+    /// We were asked to stop an ephemeral runner after the VMI started.
+    ///
+    /// This is how ARC scales down runners once their job is done.
+    Stopped,
+
+    /// The VMI was running for longer than the maximum runtime.
+    MaxRuntimeExceeded,
+
+    /// The VMI did not reach `Running` within the startup timeout.
+    StartupTimedOut {
+        /// The last phase we observed before giving up.
+        last_phase: String,
+    },
+
+    /// The runner did not show up online on GitHub within the registration timeout.
+    RegistrationTimedOut,
+}
+
+/// Why a VMI has failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailureReason {
+    /// The guest has crashed or was killed.
+    ///
+    /// This is usually caused by the job itself.
+    Guest,
+
+    /// The VMI was evicted or lost its node.
+    Infrastructure,
+}
+
+impl VmiOutcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Succeeded => "Succeeded",
+            Self::Failed { .. } => "Failed",
+            Self::Deleted => "Deleted",
+            Self::WatchInterrupted => "WatchInterrupted",
+            Self::Stopped => "Stopped",
+            Self::MaxRuntimeExceeded => "MaxRuntimeExceeded",
+            Self::StartupTimedOut { .. } => "StartupTimedOut",
+            Self::RegistrationTimedOut => "RegistrationTimedOut",
+        }
+    }
+
+    /// Returns the exit code for the outcome.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::Succeeded | Self::Stopped => 0,
+            Self::Failed {
+                reason: FailureReason::Infrastructure,
+                ..
+            } => 2,
+            Self::Failed {
+                reason: FailureReason::Guest,
+                ..
+            } => 8,
+            Self::Deleted => 3,
+            Self::WatchInterrupted => 4,
+            Self::MaxRuntimeExceeded => 5,
+            Self::StartupTimedOut { .. } => 6,
+            Self::RegistrationTimedOut => 7,
+        }
+    }
+
+    /// Returns whether the VMI timed out before it was scheduled to a node.
+    ///
+    /// This includes launcher pods that cannot pull the image. The
+    /// guest has never run, so another template may work.
+    fn never_scheduled(&self) -> bool {
+        matches!(
+            self,
+            Self::StartupTimedOut { last_phase }
+                if matches!(last_phase.as_str(), "Unknown" | "Pending" | "Scheduling")
+        )
+    }
+
+    /// Returns whether the VMI has failed before it reached `Running`.
+    fn failed_to_boot(&self) -> bool {
+        matches!(self, Self::Failed { booted: false, .. })
+    }
+
+    fn is_abnormal(&self) -> bool {
+        self.exit_code() != 0
+    }
+}
+
+/// Prints the runner info that would be passed to the VM.
+fn print_runner_info(opts: &Opts, print_opts: &PrintRunnerInfoOpts) -> AnyResult<()> {
+    let mut runner_info = build_runner_info(opts, &opts.env)?;
+    if !print_opts.show_secrets {
+        runner_info.redact();
+    }
+
+    println!("{}", serde_json::to_string_pretty(&runner_info)?);
+    Ok(())
+}
+
+/// Runs the command line interface with parsed options.
+///
+/// Returns the exit code of the process.
+pub async fn cli(mut opts: Opts) -> i32 {
+    if let Err(e) = opts.resolve_name() {
+        eprintln!("Error: {}", e);
+        return 1;
+    }
+
+    let log_format = opts.log_format;
+    let error_linger = opts.error_linger.unwrap_or(DEFAULT_ERROR_LINGER);
+    let success_hold = opts.success_hold;
+    if let Err(e) = telemetry::init(log_format, opts.otlp_endpoint.as_deref()) {
+        eprintln!("Error: {:#}", e);
+        return 1;
+    }
+
+    let run_id = format!("{:016x}", rand::thread_rng().gen::<u64>());
+    let span = tracing::info_span!(
+        "run",
+        otel.kind = "internal",
+        run_id = %run_id,
+        vmi_name = %opts.name,
+        vmi_uid = tracing::field::Empty,
+        namespace = tracing::field::Empty,
+    );
+
+    let started = Instant::now();
+    let summary_file = opts.summary_file.clone();
+    let (health_addr, health_stall_timeout) = (opts.health_addr, opts.health_stall_timeout);
+    let mut health_server = None;
+    let mut summary = Summary::default();
+    let result = match opts.command {
+        Some(Command::Gc(ref gc_opts)) => gc(&opts, gc_opts)
+            .instrument(span)
+            .await
+            .map_err(Into::into),
+        Some(Command::PrintRunnerInfo(ref print_opts)) => {
+            print_runner_info(&opts, print_opts).map_err(Into::into)
+        }
+        None => {
+            let stop = StopHandle::new();
+            match forward_signals(stop.clone()) {
+                Ok(()) => match Launcher::builder().opts(opts).build() {
+                    Ok(launcher) => {
+                        // Kept until we exit, so it reports the outcome while we linger
+                        health_server = health_addr.and_then(|addr| {
+                            HealthServer::start(addr, launcher.state(), health_stall_timeout)
+                        });
+                        launcher.run(&mut summary, &stop).instrument(span).await
+                    }
+                    Err(e) => Err(e),
+                },
+                Err(e) => Err(e.into()),
+            }
+        }
+    };
+
+    if let Some(summary_file) = &summary_file {
+        summary.duration_seconds = started.elapsed().as_secs_f64();
+        summary.error = result.as_ref().err().map(|e| format!("{:#}", e));
+
+        let written = serde_json::to_value(&summary)
+            .map_err(Into::into)
+            .and_then(|summary| write_json_file(summary_file, &summary));
+        if let Err(e) = written {
+            tracing::warn!(
+                "Failed to write summary file {}: {}",
+                summary_file.display(),
+                e
+            );
+        }
+    }
+
+    let code = match result {
+        Ok(()) => {
+            if success_hold && !error_linger.is_zero() {
+                tracing::info!("Exiting in {:?}...", error_linger);
+                io::stdout().flush().ok();
+                tokio::time::sleep(error_linger).await;
+            }
+
+            0
+        }
+        Err(e) => {
+            if log_format == LogFormat::Json {
+                tracing::error!(run_id = %run_id, "Error: {}", e);
+            } else {
+                eprintln!("Error: {}", e);
+            }
+
+            // Makes it easier to get logs (the controller deletes us immediately)
+            if !error_linger.is_zero() {
+                if log_format == LogFormat::Json {
+                    tracing::info!(run_id = %run_id, "Exiting in {:?}...", error_linger);
+                } else {
+                    eprintln!("Exiting in {:?}...", error_linger);
+                }
+
+                io::stdout().flush().ok();
+                tokio::time::sleep(error_linger).await;
+            }
+
+            e.exit_code()
+        }
+    };
+
+    if let Some(health_server) = health_server {
+        health_server.shutdown().await;
+    }
+
+    telemetry::shutdown().await;
+    code
+}
+
+/// Returns the name of the VMI, or its prefix with `VmiNameStrategy::Generate`.
+///
+/// The base name is truncated so that names with a hash stay within 63
+/// characters, which KubeVirt requires for the virt-launcher pod.
+fn vmi_name(
+    base: &str,
+    strategy: VmiNameStrategy,
+    run_id: Option<&str>,
+    pod_uid: Option<&str>,
+) -> AnyResult<String> {
+    let seed = match strategy {
+        VmiNameStrategy::Fixed | VmiNameStrategy::Generate => return Ok(base.to_string()),
+        VmiNameStrategy::RunId => {
+            run_id.ok_or_else(|| anyhow!("GITHUB_RUN_ID must be set to name the VMI after it"))?
+        }
+        VmiNameStrategy::PodUid => {
+            pod_uid.ok_or_else(|| anyhow!("POD_UID must be set to name the VMI after it"))?
+        }
+    };
+
+    let hash = short_hash(seed);
+    let base: String = base.chars().take(63 - hash.len() - 1).collect();
+    Ok(format!("{}-{}", base.trim_end_matches(['-', '.']), hash))
+}
+
+/// Returns a short, stable hash of a string for use in names.
+fn short_hash(s: &str) -> String {
+    // FNV-1a, which unlike `DefaultHasher` is stable across releases
+    let hash = s.bytes().fold(0xcbf29ce484222325u64, |hash, b| {
+        (hash ^ b as u64).wrapping_mul(0x100000001b3)
+    });
+    format!("{:06x}", hash & 0xffffff)
+}
+
+/// Turns a name into a valid DNS label (RFC 1123).
+///
+/// Invalid characters are replaced with `-`. Names longer than 63
+/// characters are truncated and get a hash of the original name.
+fn dns_label(name: &str) -> String {
+    const MAX_LEN: usize = 63;
+
+    let label: String = name
+        .to_ascii_lowercase()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect();
+    let label = label.trim_matches('-');
+
+    if !label.is_empty() && label.len() <= MAX_LEN {
+        return label.to_string();
+    }
+
+    let hash = short_hash(name);
+    let prefix = label[..label.len().min(MAX_LEN - hash.len() - 1)].trim_end_matches('-');
+    if prefix.is_empty() {
+        hash
+    } else {
+        format!("{}-{}", prefix, hash)
+    }
+}
+
+/// Generates a random suffix for names, like the API server does for `generateName`.
+fn random_suffix() -> String {
+    const ALPHABET: &[u8] = b"bcdfghjklmnpqrstvwxz2456789";
+
+    let mut rng = rand::thread_rng();
+    (0..5)
+        .map(|_| ALPHABET[rng.gen_range(0..ALPHABET.len())] as char)
+        .collect()
+}
+
+/// Deletes orphaned runner VMIs.
+async fn gc(opts: &Opts, gc_opts: &GcOpts) -> AnyResult<()> {
+    let client = Client::try_default().await?;
+    let namespace = &resolve_namespace(opts.namespace.as_deref(), &opts.env, &client);
+    tracing::Span::current().record("namespace", namespace);
+
+    let (_vm_resource, vmi_resource) = discover_kubevirt(&client, opts.setup_retries).await?;
+    let vmis: Api<VirtualMachineInstance> =
+        Api::namespaced_with(client.clone(), namespace, &vmi_resource);
+    let pods: Api<Pod> = Api::namespaced(client.clone(), namespace);
+
+    gc::collect_garbage(
+        &vmis,
+        &pods,
+        &opts.runner_info_annotation,
+        gc_opts.older_than,
+        gc_opts.confirm,
+    )
+    .await
+}
+
+/// Determines the namespace to operate in, logging where it came from.
+///
+/// In order of precedence, this is `--namespace`, `POD_NAMESPACE`, the
+/// namespace of the service account, and the default namespace of
+/// the client.
+fn resolve_namespace(
+    namespace: Option<&str>,
+    env: &BTreeMap<String, String>,
+    client: &Client,
+) -> String {
+    let non_empty = |ns: String| {
+        let ns = ns.trim().to_string();
+        (!ns.is_empty()).then_some(ns)
+    };
+
+    let (namespace, source) = if let Some(namespace) = namespace {
+        (namespace.to_string(), "--namespace")
+    } else if let Some(namespace) = env.get("POD_NAMESPACE").cloned().and_then(non_empty) {
+        (namespace, "POD_NAMESPACE")
+    } else if let Some(namespace) = fs::read_to_string(SERVICE_ACCOUNT_NAMESPACE_FILE)
+        .ok()
+        .and_then(non_empty)
+    {
+        (namespace, "the service account")
+    } else {
+        (client.default_namespace().to_string(), "the client config")
+    };
+
+    tracing::info!("Operating in namespace {} (from {})", namespace, source);
+    namespace
+}
+
+/// Discovers the VirtualMachine and VirtualMachineInstance resources.
+///
+/// Transient errors (e.g., the API server not being reachable yet)
+/// are retried up to `retries` times with exponential backoff.
+async fn discover_kubevirt(client: &Client, retries: u32) -> AnyResult<(ApiResource, ApiResource)> {
+    let mut backoff = Duration::from_secs(1);
+    let mut attempt = 0;
+    let kubevirt = loop {
+        attempt += 1;
+
+        let e = match discovery::group(client, "kubevirt.io").await {
+            Ok(kubevirt) => break kubevirt,
+            Err(e) => e,
+        };
+
+        if attempt > retries || !is_transient_error(&e) {
+            return Err(e).context("Failed to get kubevirt.io API group");
+        }
+
+        tracing::warn!(
+            "Failed to get kubevirt.io API group: {} - Retrying in {:?} ({}/{})",
+            e,
+            backoff,
+            attempt,
+            retries
+        );
+        tokio::time::sleep(backoff).await;
+        backoff *= 2;
+    };
+    let (vm_resource, _vm_caps) = kubevirt
+        .recommended_kind("VirtualMachine")
+        .ok_or_else(|| anyhow!("The kubevirt.io API group doesn't have the VirtualMachine type"))?;
+    let (vmi_resource, _vmi_caps) = kubevirt
+        .recommended_kind("VirtualMachineInstance")
+        .ok_or_else(|| {
+            anyhow!("The kubevirt.io API group doesn't have the VirtualMachineInstance type")
+        })?;
+
+    Ok((vm_resource, vmi_resource))
+}
+
+/// Atomically writes a JSON file.
+///
+/// The file is written to a temporary file next to it first,
+/// so readers never see a partial file.
+fn write_json_file(path: &Path, value: &Value) -> AnyResult<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+
+    fs::write(&tmp, serde_json::to_vec(value)?)?;
+    fs::rename(&tmp, path)?;
+
+    Ok(())
+}
+
+/// Requests a stop on `stop` when we get a signal.
+///
+/// SIGTERM is a `Terminate` request, and SIGINT, SIGHUP and SIGQUIT
+/// are `Interrupt` requests. They all lead to the VMI being cleaned
+/// up, instead of killing us (or being ignored) and leaking the VMI.
+fn forward_signals(stop: StopHandle) -> AnyResult<()> {
+    let mut sigterm = signal(SignalKind::terminate()).context("Failed to watch SIGTERM")?;
+    let mut interrupts = Interrupts::new()?;
+
+    tokio::spawn(async move {
+        loop {
+            let request = tokio::select! {
+                _ = sigterm.recv() => {
+                    tracing::info!("Got SIGTERM");
+                    StopRequest::Terminate
+                }
+                signal = interrupts.recv() => {
+                    tracing::info!("Got {}", signal);
+                    StopRequest::Interrupt
+                }
+            };
+            stop.request(request);
+        }
+    });
+
+    Ok(())
+}
+
+/// Signals other than SIGTERM that stop the launcher.
+struct Interrupts {
+    sigint: Signal,
+    sighup: Signal,
+    sigquit: Signal,
+}
+
+impl Interrupts {
+    fn new() -> AnyResult<Self> {
+        Ok(Self {
+            sigint: signal(SignalKind::interrupt()).context("Failed to watch SIGINT")?,
+            sighup: signal(SignalKind::hangup()).context("Failed to watch SIGHUP")?,
+            sigquit: signal(SignalKind::quit()).context("Failed to watch SIGQUIT")?,
+        })
+    }
+
+    /// Waits for one of the signals and returns its name.
+    async fn recv(&mut self) -> &'static str {
+        tokio::select! {
+            _ = self.sigint.recv() => "SIGINT",
+            _ = self.sighup.recv() => "SIGHUP",
+            _ = self.sigquit.recv() => "SIGQUIT",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use kube::ResourceExt;
+
+    use super::*;
+    use error::RunnerError;
+
+    #[test]
+    fn test_exit_codes() {
+        let cases = [
+            (0, VmiOutcome::Succeeded),
+            (0, VmiOutcome::Stopped),
+            (
+                2,
+                VmiOutcome::Failed {
+                    reason: FailureReason::Infrastructure,
+                    booted: true,
+                },
+            ),
+            (3, VmiOutcome::Deleted),
+            (4, VmiOutcome::WatchInterrupted),
+            (5, VmiOutcome::MaxRuntimeExceeded),
+            (
+                6,
+                VmiOutcome::StartupTimedOut {
+                    last_phase: "Pending".to_string(),
+                },
+            ),
+            (7, VmiOutcome::RegistrationTimedOut),
+            (
+                8,
+                VmiOutcome::Failed {
+                    reason: FailureReason::Guest,
+                    booted: true,
+                },
+            ),
+        ];
+
+        for (expected, outcome) in cases {
+            assert_eq!(expected, outcome.exit_code(), "{:?}", outcome);
+            assert_eq!(expected != 0, outcome.is_abnormal(), "{:?}", outcome);
+        }
+
+        let error = RunnerError::Outcome {
+            outcome: VmiOutcome::Deleted,
+            message: "VMI outcome: Deleted".to_string(),
+        };
+        assert_eq!(VmiOutcome::Deleted.exit_code(), error.exit_code());
+        assert_eq!(Some(&VmiOutcome::Deleted), error.outcome());
+
+        let error = RunnerError::Template(anyhow!("VM template not found"));
+        assert_eq!(1, error.exit_code());
+        assert_eq!("VM template not found", error.to_string());
+    }
+
+    #[test]
+    fn test_never_scheduled() {
+        let timed_out = |phase: &str| VmiOutcome::StartupTimedOut {
+            last_phase: phase.to_string(),
+        };
+
+        assert!(timed_out("Pending").never_scheduled());
+        assert!(timed_out("Scheduling").never_scheduled());
+        assert!(!timed_out("Scheduled").never_scheduled());
+        assert!(!VmiOutcome::Failed {
+            reason: FailureReason::Guest,
+            booted: false,
+        }
+        .never_scheduled());
+    }
+
+    #[test]
+    fn test_is_runner_vmi() {
+        let mut vmi: VirtualMachineInstance = serde_json::from_value(serde_json::json!({
+            "apiVersion": "kubevirt.io/v1",
+            "kind": "VirtualMachineInstance",
+            "metadata": {
+                "name": "runner",
+                "labels": {
+                    MANAGED_BY_LABEL: MANAGED_BY,
+                },
+            },
+            "spec": {},
+        }))
+        .unwrap();
+        assert!(gc::is_runner_vmi(&vmi, RUNNER_INFO_ANNOTATION));
+
+        vmi.labels_mut()
+            .insert(MANAGED_BY_LABEL.to_string(), "Helm".to_string());
+        assert!(!gc::is_runner_vmi(&vmi, RUNNER_INFO_ANNOTATION));
+
+        vmi.annotations_mut()
+            .insert(RUNNER_INFO_ANNOTATION.to_string(), "{}".to_string());
+        assert!(gc::is_runner_vmi(&vmi, RUNNER_INFO_ANNOTATION));
+    }
+
+    #[test]
+    fn test_vmi_name() {
+        let name = |strategy| vmi_name("runner", strategy, Some("1234"), Some("abcd")).unwrap();
+
+        assert_eq!("runner", name(VmiNameStrategy::Fixed));
+        assert_eq!("runner", name(VmiNameStrategy::Generate));
+        assert_eq!(
+            format!("runner-{}", short_hash("1234")),
+            name(VmiNameStrategy::RunId)
+        );
+        assert_eq!(
+            format!("runner-{}", short_hash("abcd")),
+            name(VmiNameStrategy::PodUid)
+        );
+        assert!(vmi_name("runner", VmiNameStrategy::RunId, None, None).is_err());
+
+        let long = format!("{}-{}", "a".repeat(55), "b".repeat(20));
+        let name = vmi_name(&long, VmiNameStrategy::RunId, Some("1234"), None).unwrap();
+        assert_eq!(format!("{}-{}", "a".repeat(55), short_hash("1234")), name);
+
+        assert_eq!(6, short_hash("1234").len());
+        assert_ne!(short_hash("1234"), short_hash("1235"));
+    }
+
+    #[test]
+    fn test_failed_to_boot() {
+        let failed = |booted| VmiOutcome::Failed {
+            reason: FailureReason::Guest,
+            booted,
+        };
+
+        assert!(failed(false).failed_to_boot());
+        assert!(!failed(true).failed_to_boot());
+        assert!(!VmiOutcome::Succeeded.failed_to_boot());
+        assert!(!VmiOutcome::StartupTimedOut {
+            last_phase: "Scheduled".to_string(),
+        }
+        .failed_to_boot());
+    }
+
+    #[test]
+    fn test_dns_label() {
+        assert_eq!("runner-abc12-x7z9q", dns_label("runner-abc12-x7z9q"));
+        assert_eq!("my-runner-1", dns_label("My_Runner.1"));
+
+        let long = format!("{}-runner-x7z9q", "a".repeat(60));
+        let label = dns_label(&long);
+        assert_eq!(63, label.len());
+        assert!(label.starts_with("aaaa"));
+        assert_ne!(
+            label,
+            dns_label(&format!("{}-runner-abcde", "a".repeat(60)))
+        );
+
+        assert_eq!(short_hash("..."), dns_label("..."));
+    }
+
+    #[tokio::test]
+    async fn test_forward_signals() {
+        let cases = [
+            ("-TERM", StopRequest::Terminate),
+            ("-INT", StopRequest::Interrupt),
+            ("-HUP", StopRequest::Interrupt),
+            ("-QUIT", StopRequest::Interrupt),
+        ];
+
+        for (signal, request) in cases {
+            let stop = StopHandle::new();
+            forward_signals(stop.clone()).unwrap();
+
+            let killed = std::process::Command::new("kill")
+                .args([signal, &std::process::id().to_string()])
+                .status()
+                .unwrap();
+            assert!(killed.success());
+
+            let requested = tokio::time::timeout(Duration::from_secs(5), stop.requested())
+                .await
+                .unwrap();
+            assert_eq!(request, requested, "{}", signal);
+        }
+    }
+}
@@ -0,0 +1,1089 @@
+//! Creating and deleting VMIs and VMs.
+
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result as AnyResult};
+use k8s_openapi::api::core::v1::{Pod, Secret};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::OwnerReference;
+use kube::{
+    api::{Api, DeleteParams, ListParams, Patch, PatchParams, PostParams},
+    core::{ApiResource, ObjectMeta},
+    runtime::wait::{
+        await_condition,
+        delete::{self, delete_and_finalize},
+    },
+    Client, ResourceExt,
+};
+use rand::Rng;
+use serde_json::Value;
+use tokio::time::{timeout, Instant};
+use tracing::Instrument;
+
+use crate::{
+    error::RunnerError,
+    gc,
+    guard::VmiGuard,
+    opts::{Opts, StartMode},
+    template::Plan,
+    watch::{creation_instant, is_vmi_stopped, vmi_age},
+    VirtualMachine, VirtualMachineInstance, VirtualMachineSpec, VirtualMachineTemplate, VmiOutcome,
+    MANAGED_BY, MANAGED_BY_LABEL, OWNER_POD_LABEL,
+};
+
+/// The APIs a run works with.
+#[derive(Clone)]
+pub(crate) struct Apis {
+    pub(crate) client: Client,
+
+    /// The namespace we operate in.
+    pub(crate) namespace: String,
+
+    /// The namespace of the VM templates.
+    pub(crate) template_namespace: String,
+
+    pub(crate) vm_resource: ApiResource,
+    pub(crate) vmi_resource: ApiResource,
+
+    /// VMs in the template namespace.
+    pub(crate) templates: Api<VirtualMachine>,
+
+    /// VMs in the namespace we operate in.
+    pub(crate) vms: Api<VirtualMachine>,
+
+    pub(crate) vmis: Api<VirtualMachineInstance>,
+    pub(crate) pods: Api<Pod>,
+    pub(crate) secrets: Api<Secret>,
+}
+
+impl Apis {
+    pub(crate) fn new(
+        client: Client,
+        namespace: &str,
+        template_namespace: &str,
+        vm_resource: ApiResource,
+        vmi_resource: ApiResource,
+    ) -> Self {
+        Self {
+            namespace: namespace.to_string(),
+            template_namespace: template_namespace.to_string(),
+            templates: Api::namespaced_with(client.clone(), template_namespace, &vm_resource),
+            vms: Api::namespaced_with(client.clone(), namespace, &vm_resource),
+            vmis: Api::namespaced_with(client.clone(), namespace, &vmi_resource),
+            pods: Api::namespaced(client.clone(), namespace),
+            secrets: Api::namespaced(client.clone(), namespace),
+            client,
+            vm_resource,
+            vmi_resource,
+        }
+    }
+}
+
+/// How we came by a VMI.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Origin {
+    /// We have created it.
+    Created,
+
+    /// It was created from our pod before, e.g., before our container
+    /// was restarted. We take care of it like one we have created.
+    Resumed,
+
+    /// It was created by another runner pod that's still alive and
+    /// takes care of it.
+    Adopted,
+}
+
+/// A VMI we have created or adopted.
+pub(crate) struct Created {
+    pub(crate) vmi: VirtualMachineInstance,
+    pub(crate) origin: Origin,
+
+    /// A merge patch restoring the VM, with `--start-mode vm`.
+    pub(crate) vm_restore: Option<Value>,
+
+    /// Whether the VM uses `runStrategy` rather than `running`.
+    pub(crate) run_strategy: bool,
+
+    /// When the VMI was created.
+    pub(crate) created: Instant,
+
+    /// The runner info Secret, with `--runner-info-mode secret`.
+    pub(crate) runner_info_secret: Option<String>,
+
+    pub(crate) guard: Option<VmiGuard>,
+}
+
+/// Creates the VMI of the plan, along with its Secrets.
+///
+/// With `--start-mode vm`, the VM is started instead, and with
+/// `--start-mode create-vm`, a VM is created for the VMI. An
+/// `existing` VMI is taken over as is.
+pub(crate) async fn create(
+    apis: &Apis,
+    opts: &Opts,
+    plan: Plan,
+    existing: Option<(VirtualMachineInstance, Origin)>,
+) -> Result<Created, RunnerError> {
+    let Plan {
+        vmi,
+        mut secrets,
+        run_strategy,
+        runner_info_secret,
+    } = plan;
+    let generate_name = vmi.metadata.name.is_none();
+    let create_vm = opts.start_mode == StartMode::CreateVm;
+
+    let mut created_vm = None;
+    let mut vm_restore = None;
+    let creating = Instant::now();
+    let (vmi, origin) = match existing {
+        Some(existing) => existing,
+        None if create_vm => {
+            tracing::info!("Creating VM");
+            let (vm, vmi) = create_vm_for_vmi(
+                &apis.vms,
+                &apis.vmis,
+                &vm_for_vmi(&vmi, &apis.vm_resource),
+                opts.reclaim_existing,
+                opts.startup_timeout,
+            )
+            .instrument(tracing::info_span!("create_vm", otel.kind = "client"))
+            .await
+            .map_err(RunnerError::Create)?;
+            created_vm = Some(vm);
+            (vmi, Origin::Created)
+        }
+        None if opts.start_mode == StartMode::Vm => {
+            tracing::info!("Starting VM {}", vmi.name_any());
+            let (vmi, restore) = start_vm(
+                &apis.vms,
+                &apis.vmis,
+                &vmi,
+                run_strategy,
+                opts.startup_timeout,
+            )
+            .instrument(tracing::info_span!("start_vm", otel.kind = "client"))
+            .await
+            .map_err(RunnerError::Create)?;
+            vm_restore = Some(restore);
+            (vmi, Origin::Created)
+        }
+        None => {
+            tracing::info!("Creating VMI");
+            create_vmi(&apis.vmis, &apis.pods, &vmi, opts)
+                .instrument(tracing::info_span!("create_vmi", otel.kind = "client"))
+                .await
+                .map_err(RunnerError::Create)?
+        }
+    };
+    let vmi_name = vmi.name_any();
+    if generate_name {
+        tracing::Span::current().record("vmi_name", &vmi_name);
+        tracing::info!(
+            "Created {} {}",
+            if create_vm { "VM" } else { "VMI" },
+            vmi_name
+        );
+    }
+
+    // KubeVirt would just recreate the VMI of a running VM, and an
+    // adopted VMI is still taken care of by its runner pod
+    let namespace = &apis.namespace;
+    let guard = match opts.start_mode {
+        StartMode::Ephemeral if origin == Origin::Adopted => None,
+        StartMode::Ephemeral => Some(VmiGuard::new(namespace, &apis.vmi_resource, &vmi_name)),
+        StartMode::CreateVm => Some(VmiGuard::for_vm(namespace, &apis.vm_resource, &vmi_name)),
+        StartMode::Vm => None,
+    };
+    // An existing VMI was created by an earlier run
+    let created = if origin != Origin::Created {
+        creation_instant(&vmi).unwrap_or(creating)
+    } else {
+        creating
+    };
+
+    let (owner_resource, owner_uid) = match &created_vm {
+        Some(vm) => (&apis.vm_resource, vm.metadata.uid.as_deref()),
+        None => (&apis.vmi_resource, vmi.metadata.uid.as_deref()),
+    };
+    // An existing VMI already has its Secrets
+    if origin != Origin::Created {
+        secrets.clear();
+    }
+    if let Err(e) = create_secrets(apis, &mut secrets, &vmi_name, owner_resource, owner_uid).await {
+        // The VMI would wait for the Secrets forever
+        tracing::warn!("{:#}", e);
+        let teardown = match opts.start_mode {
+            StartMode::Vm => {
+                tracing::warn!("Stopping VM");
+                stop_vm(&apis.vms, &vmi_name, run_strategy, vm_restore.as_ref()).await
+            }
+            StartMode::CreateVm => {
+                tracing::warn!("Deleting VM");
+                delete_vm(&apis.vms, &vmi_name).await
+            }
+            StartMode::Ephemeral => {
+                tracing::warn!("Deleting VMI");
+                delete_vmi(
+                    apis.vmis.clone(),
+                    &vmi_name,
+                    Duration::ZERO,
+                    opts.deletion_timeout,
+                    opts.force_delete,
+                )
+                .await
+            }
+        };
+        if let Err(e) = teardown {
+            tracing::warn!("{:#}", e);
+        }
+
+        return Err(RunnerError::Create(e));
+    }
+
+    Ok(Created {
+        vmi,
+        origin,
+        vm_restore,
+        run_strategy,
+        created,
+        runner_info_secret,
+        guard,
+    })
+}
+
+/// Creates the Secrets of a VMI, owned by the VMI or its VM.
+///
+/// The VMI will wait for the Secrets to appear. We create them
+/// afterwards so they can be garbage-collected along with the VMI,
+/// or the VM since KubeVirt may recreate the VMI.
+async fn create_secrets(
+    apis: &Apis,
+    secrets: &mut [Secret],
+    owner_name: &str,
+    owner_resource: &ApiResource,
+    owner_uid: Option<&str>,
+) -> AnyResult<()> {
+    for secret in secrets {
+        let secret_name = secret.name_any();
+        tracing::info!("Creating Secret {}", secret_name);
+
+        let owner_uid =
+            owner_uid.ok_or_else(|| anyhow!("The created {} has no UID", owner_resource.kind))?;
+        secret.metadata.owner_references = Some(vec![OwnerReference {
+            api_version: owner_resource.api_version.clone(),
+            kind: owner_resource.kind.clone(),
+            name: owner_name.to_string(),
+            uid: owner_uid.to_string(),
+            block_owner_deletion: Some(false),
+            controller: None,
+        }]);
+
+        delete_secret(&apis.secrets, &secret_name).await?;
+        apis.secrets
+            .create(&PostParams::default(), secret)
+            .await
+            .with_context(|| format!("Failed to create Secret {}", secret_name))?;
+    }
+
+    Ok(())
+}
+
+/// Whether the VMI is kept for debugging after the outcome.
+pub(crate) fn keep_vmi(opts: &Opts, outcome: &VmiOutcome) -> bool {
+    opts.keep_vmi_on_failure
+        && matches!(
+            outcome,
+            VmiOutcome::Failed { .. }
+                | VmiOutcome::WatchInterrupted
+                | VmiOutcome::StartupTimedOut { .. }
+        )
+}
+
+/// Deletes or stops what we have created, along with the runner info Secret.
+pub(crate) async fn cleanup(
+    apis: &Apis,
+    opts: &Opts,
+    created: &mut Created,
+    outcome: &VmiOutcome,
+) -> Result<(), RunnerError> {
+    // From here on, the VM is taken care of
+    if let Some(guard) = &mut created.guard {
+        guard.disarm();
+    }
+
+    // Keep going after errors so nothing is left behind, and return the first
+    let mut result = Ok(());
+    let vmi_name = created.vmi.name_any();
+    if created.origin == Origin::Adopted {
+        tracing::info!(
+            "Leaving adopted VMI {} to the runner pod that created it",
+            vmi_name
+        );
+    } else if keep_vmi(opts, outcome) {
+        if opts.start_mode == StartMode::CreateVm {
+            // KubeVirt would boot a new runner with the same registration otherwise
+            tracing::info!("Halting VM");
+            keep_first_error(&mut result, halt_vm(&apis.vms, &vmi_name).await);
+        }
+        tracing::warn!(
+            "Keeping VMI {} for debugging - Inspect it with `kubectl -n {} describe vmi {}` and delete it when done",
+            vmi_name,
+            apis.namespace,
+            vmi_name
+        );
+    } else if opts.start_mode == StartMode::Vm {
+        // Even if the VMI was deleted, KubeVirt would recreate it
+        tracing::info!("Stopping VM");
+        let stopped = stop_vm(
+            &apis.vms,
+            &vmi_name,
+            created.run_strategy,
+            created.vm_restore.as_ref(),
+        )
+        .await;
+
+        if stopped.is_ok() && opts.wait_for_deletion {
+            wait_for_deletion(&apis.vmis, &vmi_name, opts.deletion_timeout).await;
+        }
+        keep_first_error(&mut result, stopped);
+    } else if opts.start_mode == StartMode::CreateVm {
+        // Halting lets the guest shut down gracefully before the VM is gone
+        tracing::info!("Stopping VM");
+        let halted = halt_vm(&apis.vms, &vmi_name).await;
+        if halted.is_ok() && opts.wait_for_deletion {
+            wait_for_deletion(&apis.vmis, &vmi_name, opts.deletion_timeout).await;
+        }
+        keep_first_error(&mut result, halted);
+
+        tracing::info!("Deleting VM");
+        keep_first_error(&mut result, delete_vm(&apis.vms, &vmi_name).await);
+    } else if *outcome != VmiOutcome::Deleted {
+        tracing::info!("Deleting VMI");
+        let deleted = delete_vmi(
+            apis.vmis.clone(),
+            &vmi_name,
+            opts.shutdown_grace,
+            opts.deletion_timeout,
+            opts.force_delete,
+        )
+        .await;
+        keep_first_error(&mut result, deleted);
+    }
+
+    // Don't leave the token around until the Secret is garbage-collected
+    if let Some(secret) = created
+        .runner_info_secret
+        .as_ref()
+        .filter(|_| created.origin != Origin::Adopted)
+    {
+        tracing::info!("Deleting runner info Secret");
+        keep_first_error(&mut result, delete_secret(&apis.secrets, secret).await);
+    }
+
+    result.map_err(Into::into)
+}
+
+/// Keeps the first error in `result`, logging later ones.
+fn keep_first_error(result: &mut AnyResult<()>, step: AnyResult<()>) {
+    if let Err(e) = step {
+        if result.is_ok() {
+            *result = Err(e);
+        } else {
+            tracing::warn!("{:#}", e);
+        }
+    }
+}
+
+/// Starts a stopped VM with the metadata and spec of `vmi` as its template.
+///
+/// Returns the VMI once KubeVirt has created it, and a merge patch
+/// restoring the original template of the VM.
+pub(crate) async fn start_vm(
+    vms: &Api<VirtualMachine>,
+    vmis: &Api<VirtualMachineInstance>,
+    vmi: &VirtualMachineInstance,
+    run_strategy: bool,
+    wait: Duration,
+) -> AnyResult<(VirtualMachineInstance, Value)> {
+    let name = vmi.name_any();
+    if vmis.get_opt(&name).await?.is_some() {
+        return Err(anyhow!("VM {} is already running", name));
+    }
+    let original = vms
+        .get(&name)
+        .await
+        .with_context(|| format!("Failed to get VM {}", name))?;
+
+    // KubeVirt sets the VM as the owner of the VMI
+    let mut patch = serde_json::json!({
+        "spec": {
+            "template": {
+                "metadata": {
+                    "labels": vmi.metadata.labels,
+                    "annotations": vmi.metadata.annotations,
+                },
+                "spec": vmi.spec,
+            },
+        },
+    });
+    if run_strategy {
+        patch["spec"]["runStrategy"] = "Always".into();
+    } else {
+        patch["spec"]["running"] = true.into();
+    }
+
+    let started = vms
+        .patch(&name, &PatchParams::default(), &Patch::Merge(&patch))
+        .await
+        .with_context(|| format!("Failed to start VM {}", name))?;
+    let restore = merge_patch(
+        &serde_json::to_value(&started.spec.template)?,
+        &serde_json::to_value(&original.spec.template)?,
+    );
+
+    let vmi = await_vm_vmi(vmis, &name, wait).await?;
+    Ok((vmi, restore))
+}
+
+/// Returns a JSON merge patch turning `from` into `to`.
+fn merge_patch(from: &Value, to: &Value) -> Value {
+    let (Value::Object(from), Value::Object(to)) = (from, to) else {
+        return to.clone();
+    };
+
+    let mut patch = serde_json::Map::new();
+    for key in from.keys().filter(|key| !to.contains_key(*key)) {
+        patch.insert(key.clone(), Value::Null);
+    }
+    for (key, value) in to {
+        match from.get(key) {
+            Some(old) if old == value => {}
+            Some(old) => {
+                patch.insert(key.clone(), merge_patch(old, value));
+            }
+            None => {
+                patch.insert(key.clone(), value.clone());
+            }
+        }
+    }
+
+    Value::Object(patch)
+}
+
+/// Returns a VM with the metadata and spec of `vmi` as its template.
+///
+/// The VM takes the name, labels and owner references of `vmi`.
+pub(crate) fn vm_for_vmi(vmi: &VirtualMachineInstance, resource: &ApiResource) -> VirtualMachine {
+    let mut vm = VirtualMachine::new(
+        "vm",
+        resource,
+        VirtualMachineSpec {
+            template: VirtualMachineTemplate {
+                metadata: ObjectMeta {
+                    labels: vmi.metadata.labels.clone(),
+                    annotations: vmi.metadata.annotations.clone(),
+                    ..Default::default()
+                },
+                spec: vmi.spec.clone(),
+            },
+            run_strategy: Some("RerunOnFailure".to_string()),
+        },
+    );
+    vm.metadata = ObjectMeta {
+        name: vmi.metadata.name.clone(),
+        generate_name: vmi.metadata.generate_name.clone(),
+        namespace: vmi.metadata.namespace.clone(),
+        labels: vmi.metadata.labels.clone(),
+        owner_references: vmi.metadata.owner_references.clone(),
+        ..Default::default()
+    };
+
+    vm
+}
+
+/// Creates a VM and returns it along with the VMI KubeVirt creates for it.
+///
+/// If a VM with the same name already exists and was created by us,
+/// it's deleted first if `reclaim` is set.
+pub(crate) async fn create_vm_for_vmi(
+    vms: &Api<VirtualMachine>,
+    vmis: &Api<VirtualMachineInstance>,
+    vm: &VirtualMachine,
+    reclaim: bool,
+    wait: Duration,
+) -> AnyResult<(VirtualMachine, VirtualMachineInstance)> {
+    let created = match vms.create(&PostParams::default(), vm).await {
+        Ok(created) => created,
+        Err(kube::Error::Api(e)) if e.code == 409 => {
+            let name = vm.name_any();
+            let existing = vms.get(&name).await?;
+            let managed = existing.labels().get(MANAGED_BY_LABEL).map(String::as_str);
+            if !reclaim || managed != Some(MANAGED_BY) {
+                return Err(anyhow!(
+                    "VM {} already exists - Delete it or enable --reclaim-existing",
+                    name
+                ));
+            }
+
+            tracing::warn!("Deleting VM {} left over from a previous run", name);
+            timeout(
+                wait,
+                delete_and_finalize(vms.clone(), &name, &DeleteParams::foreground()),
+            )
+            .await
+            .map_err(|_| anyhow!("VM {} was not deleted within {:?}", name, wait))?
+            .with_context(|| format!("Failed to delete VM {}", name))?;
+
+            vms.create(&PostParams::default(), vm)
+                .await
+                .context("Failed to create VM")?
+        }
+        Err(e) => return Err(e).context("Failed to create VM"),
+    };
+
+    let vmi = await_vm_vmi(vmis, &created.name_any(), wait).await?;
+    Ok((created, vmi))
+}
+
+/// Waits for KubeVirt to create the VMI of a VM.
+async fn await_vm_vmi(
+    vmis: &Api<VirtualMachineInstance>,
+    name: &str,
+    wait: Duration,
+) -> AnyResult<VirtualMachineInstance> {
+    let created = |vmi: Option<&VirtualMachineInstance>| vmi.is_some();
+    timeout(wait, await_condition(vmis.clone(), name, created))
+        .await
+        .map_err(|_| anyhow!("VM {} did not create a VMI within {:?}", name, wait))??
+        .ok_or_else(|| anyhow!("The VMI of VM {} disappeared", name))
+}
+
+/// Deletes a VM created by `create_vm_for_vmi`, along with its VMI.
+pub(crate) async fn delete_vm(vms: &Api<VirtualMachine>, name: &str) -> AnyResult<()> {
+    match vms.delete(name, &DeleteParams::default()).await {
+        Ok(_) => Ok(()),
+        Err(kube::Error::Api(e)) if e.code == 404 => Ok(()),
+        Err(e) => Err(e).with_context(|| format!("Failed to delete VM {}", name)),
+    }
+}
+
+/// Stops a VM started by `start_vm`.
+///
+/// `restore` is the patch returned by `start_vm`, which removes the
+/// runner info and the volumes we added from its template.
+pub(crate) async fn stop_vm(
+    vms: &Api<VirtualMachine>,
+    name: &str,
+    run_strategy: bool,
+    restore: Option<&Value>,
+) -> AnyResult<()> {
+    let mut patch = Value::Null;
+    if let Some(restore) = restore {
+        patch["spec"]["template"] = restore.clone();
+    }
+    if run_strategy {
+        patch["spec"]["runStrategy"] = "Halted".into();
+    } else {
+        patch["spec"]["running"] = false.into();
+    }
+
+    patch_vm_to_stop(vms, name, &patch).await
+}
+
+/// Halts a VM created by `create_vm_for_vmi`.
+///
+/// KubeVirt shuts the guest down and doesn't restart it anymore.
+pub(crate) async fn halt_vm(vms: &Api<VirtualMachine>, name: &str) -> AnyResult<()> {
+    let patch = serde_json::json!({
+        "spec": {
+            "runStrategy": "Halted",
+        },
+    });
+
+    patch_vm_to_stop(vms, name, &patch).await
+}
+
+/// Applies a merge patch stopping a VM, which may be gone already.
+async fn patch_vm_to_stop(vms: &Api<VirtualMachine>, name: &str, patch: &Value) -> AnyResult<()> {
+    match vms
+        .patch(name, &PatchParams::default(), &Patch::Merge(patch))
+        .await
+    {
+        Ok(_) => Ok(()),
+        Err(kube::Error::Api(e)) if e.code == 404 => Ok(()),
+        Err(e) => Err(e).with_context(|| format!("Failed to stop VM {}", name)),
+    }
+}
+
+/// Deletes a VMI left over from a previous run, if any.
+///
+/// If the VMI hasn't finished and isn't older than `--adopt-max-age`,
+/// it's returned to be taken over instead when it was created from
+/// our own pod (e.g., if only our container was restarted), or when
+/// the runner pod that created it is still alive (e.g., during a
+/// rollout). VMIs are only deleted with `--reclaim-existing` and if
+/// they were created by us.
+pub(crate) async fn adopt_or_delete_existing_vmi(
+    api: &Api<VirtualMachineInstance>,
+    pods: &Api<Pod>,
+    name: &str,
+    opts: &Opts,
+) -> AnyResult<Option<(VirtualMachineInstance, Origin)>> {
+    let Some(existing) = api.get_opt(name).await? else {
+        return Ok(None);
+    };
+
+    let age = vmi_age(&existing);
+    if let Some((owner, owner_uid)) = adoptable_owner(&existing) {
+        if age.is_some_and(|age| age > opts.adopt_max_age) {
+            tracing::info!(
+                "The VMI already exists but was created {:?} ago, longer than --adopt-max-age - Not adopting it",
+                age.unwrap_or_default()
+            );
+        } else if opts.pod_name.as_deref() == Some(owner)
+            || owner_uid.is_some() && owner_uid == opts.pod_uid.as_deref()
+        {
+            // Even with another UID, the pod that created it is gone
+            tracing::info!(
+                "The VMI already exists and was created from our pod {} (was our container restarted?) - Resuming it",
+                owner
+            );
+            return Ok(Some((existing, Origin::Resumed)));
+        } else {
+            match pods.get_opt(owner).await {
+                Ok(Some(pod)) if is_pod_alive(&pod, owner_uid) => {
+                    tracing::info!(
+                        "The VMI already exists and its runner pod {} is still alive - Adopting it",
+                        owner
+                    );
+                    return Ok(Some((existing, Origin::Adopted)));
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    tracing::warn!("Failed to get runner pod {} of the VMI: {}", owner, e);
+                }
+            }
+        }
+    }
+
+    let (reclaim, annotation) = (opts.reclaim_existing, &opts.runner_info_annotation);
+    if !reclaim {
+        return Err(anyhow!(
+            "VMI {} already exists - Delete it or enable --reclaim-existing",
+            name
+        ));
+    }
+
+    if !gc::is_runner_vmi(&existing, annotation) {
+        return Err(anyhow!(
+            "VMI {} already exists but wasn't created by us - Refusing to delete it",
+            name
+        ));
+    }
+
+    tracing::info!("The VMI already exists (were we killed?) - Deleting");
+    delete_and_finalize(api.clone(), name, &DeleteParams::default())
+        .await
+        .context("Failed to delete existing VMI")?;
+
+    Ok(None)
+}
+
+/// Returns the runner pod (name and UID, if known) of a VMI if we may adopt it.
+///
+/// Only VMIs carrying the owner pod label that haven't finished
+/// and aren't being deleted are considered.
+fn adoptable_owner(vmi: &VirtualMachineInstance) -> Option<(&str, Option<&str>)> {
+    if vmi.metadata.deletion_timestamp.is_some() {
+        return None;
+    }
+
+    if vmi
+        .status
+        .as_ref()
+        .is_some_and(|status| matches!(status.phase.as_str(), "Succeeded" | "Failed"))
+    {
+        return None;
+    }
+
+    let owner = vmi.labels().get(OWNER_POD_LABEL)?;
+    let owner_uid = vmi
+        .owner_references()
+        .iter()
+        .find(|r| r.kind == "Pod" && &r.name == owner)
+        .map(|r| r.uid.as_str());
+
+    Some((owner, owner_uid))
+}
+
+/// Returns whether a pod is alive and, if `uid` is set, is the same pod.
+fn is_pod_alive(pod: &Pod, uid: Option<&str>) -> bool {
+    pod.metadata.deletion_timestamp.is_none()
+        && (uid.is_none() || pod.metadata.uid.as_deref() == uid)
+}
+
+/// Deletes VMIs matching `selector` whose runner pod is gone.
+///
+/// Only VMIs carrying the owner pod label are considered, so
+/// VMIs of concurrently-running runners are left alone.
+pub(crate) async fn delete_orphaned_vmis(
+    vmis: &Api<VirtualMachineInstance>,
+    pods: &Api<Pod>,
+    selector: &str,
+) -> AnyResult<()> {
+    let selector = if selector.is_empty() {
+        OWNER_POD_LABEL.to_string()
+    } else {
+        format!("{},{}", selector, OWNER_POD_LABEL)
+    };
+
+    let candidates = vmis
+        .list(&ListParams::default().labels(&selector))
+        .await
+        .context("Failed to list VMIs")?;
+
+    for vmi in candidates {
+        let (Some(name), Some(owner)) = (
+            vmi.metadata.name.as_ref(),
+            vmi.labels().get(OWNER_POD_LABEL),
+        ) else {
+            continue;
+        };
+
+        match pods.get_opt(owner).await {
+            Ok(Some(_)) => continue,
+            Ok(None) => {}
+            Err(e) => {
+                tracing::warn!("Failed to get runner pod {} of VMI {}: {}", owner, name, e);
+                continue;
+            }
+        }
+
+        tracing::info!(
+            "Deleting orphaned VMI {} (pod {} no longer exists)",
+            name,
+            owner
+        );
+        match vmis.delete(name, &DeleteParams::default()).await {
+            Ok(_) => {}
+            Err(kube::Error::Api(e)) if e.code == 404 => {}
+            Err(e) => {
+                tracing::warn!("Failed to delete orphaned VMI {}: {}", name, e);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Creates the VMI, retrying on transient errors.
+///
+/// If a VMI with the same name has been created in the meantime, it
+/// may be taken over instead as in `adopt_or_delete_existing_vmi`.
+/// Returns the VMI and how we came by it.
+pub(crate) async fn create_vmi(
+    api: &Api<VirtualMachineInstance>,
+    pods: &Api<Pod>,
+    vmi: &VirtualMachineInstance,
+    opts: &Opts,
+) -> AnyResult<(VirtualMachineInstance, Origin)> {
+    let retries = opts.create_retries;
+    let name = vmi.metadata.name.as_deref().unwrap_or_default();
+    let mut backoff = Duration::from_secs(1);
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+
+        let e = match api.create(&PostParams::default(), vmi).await {
+            Ok(vmi) => return Ok((vmi, Origin::Created)),
+            Err(e) => e,
+        };
+
+        if attempt > retries || !is_transient_error(&e) {
+            return Err(e).with_context(|| {
+                format!(
+                    "Failed to create VMI after {} attempt{}",
+                    attempt,
+                    if attempt == 1 { "" } else { "s" }
+                )
+            });
+        }
+
+        // Up to 50% jitter so concurrent runners don't retry in lockstep
+        let delay = backoff.mul_f64(rand::thread_rng().gen_range(1.0..1.5));
+        tracing::warn!(
+            "Failed to create VMI: {} - Retrying in {:?} ({}/{})",
+            e,
+            delay,
+            attempt,
+            retries
+        );
+        tokio::time::sleep(delay).await;
+        backoff *= 2;
+
+        // With `generateName`, we just try again with another suffix
+        if matches!(&e, kube::Error::Api(e) if e.code == 409) && !name.is_empty() {
+            if let Some(existing) = adopt_or_delete_existing_vmi(api, pods, name, opts).await? {
+                return Ok(existing);
+            }
+        }
+    }
+}
+
+/// Returns whether an error from the API server is likely transient.
+///
+/// Conflicts are included since they are usually caused by a
+/// previous VMI that's still being deleted.
+pub(crate) fn is_transient_error(e: &kube::Error) -> bool {
+    match e {
+        kube::Error::Api(e) => matches!(e.code, 408 | 409 | 429) || e.code >= 500,
+        kube::Error::HyperError(_) | kube::Error::Service(_) => true,
+        _ => false,
+    }
+}
+
+/// Deletes the VMI, giving the guest a chance to shut down gracefully.
+///
+/// Deleting a VMI makes KubeVirt send an ACPI shutdown to the guest
+/// and wait for `terminationGracePeriodSeconds`. If the VMI hasn't
+/// stopped after `grace`, we force it off by deleting it again with
+/// a grace period of zero. With a `grace` of zero, the VMI is forced
+/// off right away.
+///
+/// If the VMI isn't gone within `deletion_timeout` after that, we
+/// give up (and force-delete it if `force` is set).
+pub(crate) async fn delete_vmi(
+    api: Api<VirtualMachineInstance>,
+    name: &str,
+    grace: Duration,
+    deletion_timeout: Duration,
+    force: bool,
+) -> AnyResult<()> {
+    let immediate = DeleteParams {
+        grace_period_seconds: Some(0),
+        ..Default::default()
+    };
+    let dp = if grace.is_zero() {
+        immediate.clone()
+    } else {
+        DeleteParams::default()
+    };
+    match api.delete(name, &dp).await {
+        Ok(_) => {}
+        Err(kube::Error::Api(e)) if e.code == 404 => return Ok(()),
+        Err(e) => return Err(e).context("Failed to delete VMI"),
+    }
+
+    if !grace.is_zero() {
+        match timeout(grace, await_condition(api.clone(), name, is_vmi_stopped)).await {
+            Ok(result) => {
+                result.context("Failed to wait for the VMI to stop")?;
+            }
+            Err(_) => {
+                tracing::warn!("VMI did not shut down within {:?} - Forcing it off", grace);
+
+                // The spec of a VMI can't be updated, but a shorter grace
+                // period in another deletion request is honored
+                match api.delete(name, &immediate).await {
+                    Ok(_) => {}
+                    Err(kube::Error::Api(e)) if e.code == 404 => return Ok(()),
+                    Err(e) => return Err(e).context("Failed to force the VMI off"),
+                }
+            }
+        }
+    }
+
+    let finalize = delete_and_finalize(api.clone(), name, &dp);
+    match timeout(deletion_timeout, finalize).await {
+        // It may be gone already, e.g., once it has stopped within the grace period
+        Ok(Err(delete::Error::Delete(kube::Error::Api(e)))) if e.code == 404 => {}
+        Ok(result) => result.context("Failed to delete VMI")?,
+        Err(_) if force => {
+            tracing::warn!(
+                "VMI {} is still around after {:?} - Force-deleting it",
+                name,
+                deletion_timeout
+            );
+
+            match api.delete(name, &immediate).await {
+                Ok(_) => {}
+                Err(kube::Error::Api(e)) if e.code == 404 => {}
+                Err(e) => return Err(e).context("Failed to force-delete VMI"),
+            }
+        }
+        Err(_) => {
+            tracing::warn!(
+                "VMI {} is still around after {:?} - Not waiting for it anymore",
+                name,
+                deletion_timeout
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Waits until the VMI no longer exists.
+///
+/// This only warns on errors, since the VMI is already being deleted.
+pub(crate) async fn wait_for_deletion(
+    api: &Api<VirtualMachineInstance>,
+    name: &str,
+    deletion_timeout: Duration,
+) {
+    let deadline = Instant::now() + deletion_timeout;
+    loop {
+        match api.get_opt(name).await {
+            Ok(None) => return,
+            Ok(Some(_)) => {}
+            Err(e) => {
+                tracing::warn!("Failed to check whether the VMI is gone: {}", e);
+            }
+        }
+
+        if Instant::now() >= deadline {
+            tracing::warn!(
+                "VMI still exists after {:?} - Exiting anyway",
+                deletion_timeout
+            );
+            return;
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+}
+
+/// Deletes a Secret if it exists.
+pub(crate) async fn delete_secret(api: &Api<Secret>, name: &str) -> AnyResult<()> {
+    match api.delete(name, &DeleteParams::default()).await {
+        Ok(_) => Ok(()),
+        Err(kube::Error::Api(e)) if e.code == 404 => Ok(()),
+        Err(e) => Err(e).with_context(|| format!("Failed to delete Secret {}", name)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RUNNER_INFO_ANNOTATION;
+
+    #[test]
+    fn test_keep_first_error() {
+        let mut result = Ok(());
+        keep_first_error(&mut result, Ok(()));
+        assert!(result.is_ok());
+
+        keep_first_error(&mut result, Err(anyhow!("first")));
+        keep_first_error(&mut result, Ok(()));
+        keep_first_error(&mut result, Err(anyhow!("second")));
+        assert_eq!("first", result.unwrap_err().to_string());
+    }
+
+    #[test]
+    fn test_merge_patch() {
+        let from = serde_json::json!({
+            "metadata": {
+                "annotations": { "keep": "a", "runner-info": "{}" },
+            },
+            "spec": {
+                "volumes": [{ "name": "disk" }, { "name": "runner-info" }],
+                "domain": { "cpu": { "cores": 2 } },
+            },
+        });
+        let to = serde_json::json!({
+            "metadata": {
+                "annotations": { "keep": "a" },
+            },
+            "spec": {
+                "volumes": [{ "name": "disk" }],
+                "domain": { "cpu": { "cores": 2 } },
+            },
+        });
+
+        assert_eq!(
+            serde_json::json!({
+                "metadata": {
+                    "annotations": { "runner-info": null },
+                },
+                "spec": {
+                    "volumes": [{ "name": "disk" }],
+                },
+            }),
+            merge_patch(&from, &to)
+        );
+    }
+
+    #[test]
+    fn test_adoptable_owner() {
+        let mut vmi: VirtualMachineInstance = serde_json::from_value(serde_json::json!({
+            "apiVersion": "kubevirt.io/v1",
+            "kind": "VirtualMachineInstance",
+            "metadata": {
+                "name": "runner",
+                "labels": {
+                    OWNER_POD_LABEL: "runner",
+                },
+                "ownerReferences": [
+                    {
+                        "apiVersion": "v1",
+                        "kind": "Pod",
+                        "name": "runner",
+                        "uid": "1234",
+                    },
+                ],
+            },
+            "spec": {},
+            "status": {
+                "phase": "Running",
+            },
+        }))
+        .unwrap();
+        assert_eq!(Some(("runner", Some("1234"))), adoptable_owner(&vmi));
+
+        vmi.status.as_mut().unwrap().phase = "Succeeded".to_string();
+        assert_eq!(None, adoptable_owner(&vmi));
+
+        vmi.status = None;
+        vmi.labels_mut().remove(OWNER_POD_LABEL);
+        assert_eq!(None, adoptable_owner(&vmi));
+    }
+
+    #[test]
+    fn test_vm_for_vmi() {
+        let resource = ApiResource {
+            group: "kubevirt.io".to_string(),
+            version: "v1".to_string(),
+            api_version: "kubevirt.io/v1".to_string(),
+            kind: "VirtualMachine".to_string(),
+            plural: "virtualmachines".to_string(),
+        };
+        let vmi: VirtualMachineInstance = serde_json::from_value(serde_json::json!({
+            "apiVersion": "kubevirt.io/v1",
+            "kind": "VirtualMachineInstance",
+            "metadata": {
+                "name": "runner",
+                "labels": { OWNER_POD_LABEL: "runner" },
+                "annotations": { RUNNER_INFO_ANNOTATION: "{}" },
+            },
+            "spec": {
+                "domain": {},
+            },
+        }))
+        .unwrap();
+
+        let vm = vm_for_vmi(&vmi, &resource);
+        assert_eq!("VirtualMachine", vm.types.as_ref().unwrap().kind);
+        assert_eq!(Some("runner"), vm.metadata.name.as_deref());
+        assert!(vm.labels().contains_key(OWNER_POD_LABEL));
+        assert!(vm.annotations().is_empty());
+        assert_eq!(Some("RerunOnFailure"), vm.spec.run_strategy.as_deref());
+        assert!(vm
+            .spec
+            .template
+            .metadata
+            .annotations
+            .unwrap()
+            .contains_key(RUNNER_INFO_ANNOTATION));
+        assert!(vm.spec.template.spec.data.contains_key("domain"));
+    }
+}
@@ -0,0 +1,212 @@
+//! Prometheus metrics.
+
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result as AnyResult};
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Client, Method, Request, Response, Server, StatusCode,
+};
+use prometheus::{
+    exponential_buckets, Histogram, HistogramOpts, IntCounterVec, IntGaugeVec, Opts, Registry,
+    TextEncoder,
+};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+
+const NAMESPACE: &str = "kubevirt_actions_runner";
+const PUSHGATEWAY_JOB: &str = "kubevirt-actions-runner";
+
+/// Metrics about the VMI lifecycle.
+pub struct Metrics {
+    registry: Registry,
+
+    /// Time from VMI creation to `Running`.
+    startup_duration: Histogram,
+
+    /// Time from VMI creation to its outcome.
+    lifetime: Histogram,
+
+    /// Outcomes of the VMI.
+    outcomes: IntCounterVec,
+
+    /// The current phase of the VMI.
+    ///
+    /// The gauge for the current phase is 1, and all others are 0.
+    phase: IntGaugeVec,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new();
+
+        let startup_duration = Histogram::with_opts(
+            HistogramOpts::new(
+                "vmi_startup_duration_seconds",
+                "Time from VMI creation to Running",
+            )
+            .namespace(NAMESPACE)
+            .buckets(exponential_buckets(5.0, 2.0, 8).unwrap()),
+        )
+        .unwrap();
+
+        let lifetime = Histogram::with_opts(
+            HistogramOpts::new(
+                "vmi_lifetime_seconds",
+                "Time from VMI creation to its outcome",
+            )
+            .namespace(NAMESPACE)
+            .buckets(exponential_buckets(60.0, 2.0, 10).unwrap()),
+        )
+        .unwrap();
+
+        let outcomes = IntCounterVec::new(
+            Opts::new("vmi_outcomes_total", "Outcomes of the VMI").namespace(NAMESPACE),
+            &["outcome"],
+        )
+        .unwrap();
+
+        let phase = IntGaugeVec::new(
+            Opts::new("vmi_phase", "The current phase of the VMI").namespace(NAMESPACE),
+            &["phase"],
+        )
+        .unwrap();
+
+        registry
+            .register(Box::new(startup_duration.clone()))
+            .unwrap();
+        registry.register(Box::new(lifetime.clone())).unwrap();
+        registry.register(Box::new(outcomes.clone())).unwrap();
+        registry.register(Box::new(phase.clone())).unwrap();
+
+        Self {
+            registry,
+            startup_duration,
+            lifetime,
+            outcomes,
+            phase,
+        }
+    }
+
+    /// Records the time it took for the VMI to reach `Running`.
+    pub fn observe_startup(&self, duration: Duration) {
+        self.startup_duration.observe(duration.as_secs_f64());
+    }
+
+    /// Records the outcome of the VMI and how long it lived.
+    pub fn record_outcome(&self, outcome: &str, lifetime: Duration) {
+        self.outcomes.with_label_values(&[outcome]).inc();
+        self.lifetime.observe(lifetime.as_secs_f64());
+    }
+
+    /// Sets the current phase of the VMI.
+    pub fn set_phase(&self, phase: &str) {
+        self.phase.reset();
+        self.phase.with_label_values(&[phase]).set(1);
+    }
+
+    /// Renders the metrics in the Prometheus text format.
+    pub fn render(&self) -> String {
+        TextEncoder::new()
+            .encode_to_string(&self.registry.gather())
+            .unwrap_or_else(|e| {
+                tracing::warn!("Failed to encode metrics: {}", e);
+                String::new()
+            })
+    }
+
+    /// Pushes the metrics to a Prometheus Pushgateway.
+    ///
+    /// Runner pods are short-lived and may be gone before they
+    /// are scraped, so this should be called right before exiting.
+    pub async fn push(&self, pushgateway: &str, instance: &str) -> AnyResult<()> {
+        let uri = format!(
+            "{}/metrics/job/{}/instance/{}",
+            pushgateway.trim_end_matches('/'),
+            PUSHGATEWAY_JOB,
+            instance
+        );
+
+        let request = Request::put(uri).body(Body::from(self.render()))?;
+        let response = Client::new().request(request).await?;
+
+        if !response.status().is_success() {
+            return Err(anyhow!("Pushgateway returned {}", response.status()));
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A running metrics server.
+pub struct MetricsServer {
+    shutdown: oneshot::Sender<()>,
+    handle: JoinHandle<()>,
+}
+
+impl MetricsServer {
+    /// Starts serving metrics at `/metrics`.
+    ///
+    /// Failing to bind only results in a warning, since
+    /// metrics aren't essential to the run.
+    pub fn start(addr: SocketAddr, metrics: Arc<Metrics>) -> Option<Self> {
+        let builder = match Server::try_bind(&addr) {
+            Ok(builder) => builder,
+            Err(e) => {
+                tracing::warn!("Failed to bind metrics server to {}: {}", addr, e);
+                return None;
+            }
+        };
+
+        let make_service = make_service_fn(move |_| {
+            let metrics = metrics.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request| {
+                    let metrics = metrics.clone();
+                    async move { Ok::<_, Infallible>(handle_request(request, &metrics)) }
+                }))
+            }
+        });
+
+        let (shutdown, shutdown_rx) = oneshot::channel();
+        let server = builder.serve(make_service).with_graceful_shutdown(async {
+            shutdown_rx.await.ok();
+        });
+
+        tracing::info!("Serving metrics at {}", addr);
+
+        let handle = tokio::spawn(async move {
+            if let Err(e) = server.await {
+                tracing::warn!("Metrics server failed: {}", e);
+            }
+        });
+
+        Some(Self { shutdown, handle })
+    }
+
+    /// Shuts down the server.
+    pub async fn shutdown(self) {
+        self.shutdown.send(()).ok();
+        self.handle.await.ok();
+    }
+}
+
+fn handle_request(request: Request<Body>, metrics: &Metrics) -> Response<Body> {
+    match (request.method(), request.uri().path()) {
+        (&Method::GET, "/metrics") => Response::new(Body::from(metrics.render())),
+        _ => {
+            let mut response = Response::new(Body::empty());
+            *response.status_mut() = StatusCode::NOT_FOUND;
+            response
+        }
+    }
+}
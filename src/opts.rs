@@ -0,0 +1,1239 @@
+//! Command line options.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result as AnyResult};
+use clap::{Parser, Subcommand, ValueEnum};
+
+use crate::{
+    config, dns_label, CLOUD_INIT_VOLUME, RUNNER_INFO_ANNOTATION, RUNNER_INFO_PATH,
+    RUNNER_INFO_VOLUME,
+};
+
+/// Exit codes, shown in `--help`.
+///
+/// Keep in sync with `VmiOutcome::exit_code`.
+const EXIT_CODES_HELP: &str = "\
+Exit codes:
+  0  The VMI has succeeded or was stopped
+  1  Setup or configuration error
+  2  The VMI has failed because of the infrastructure
+  3  The VMI was deleted by something else
+  4  Stopped watching the VMI unexpectedly
+  5  The VMI exceeded the maximum runtime
+  6  The VMI did not start in time
+  7  The runner did not register with GitHub in time
+  8  The VMI has failed because of the guest";
+
+/// The format of log output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    /// Human-readable text.
+    Text,
+
+    /// One JSON object per line.
+    Json,
+}
+
+/// What the runner is registered to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum RunnerScope {
+    /// A repository (`owner/repo`).
+    Repo,
+
+    /// An organization.
+    Org,
+
+    /// An enterprise.
+    Enterprise,
+}
+
+/// How the VMI is named.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum VmiNameStrategy {
+    /// Use the runner name as-is.
+    ///
+    /// An existing VMI with the same name is deleted first.
+    Fixed,
+
+    /// Use the runner name as a prefix and let the API server add a unique suffix.
+    Generate,
+
+    /// Add a short hash of the workflow run ID (`GITHUB_RUN_ID`) to the runner name.
+    ///
+    /// An existing VMI with the same name is deleted first. ARC doesn't
+    /// set `GITHUB_RUN_ID` in the runner pod, so it must be added to the
+    /// pod spec.
+    RunId,
+
+    /// Add a short hash of the UID of the runner pod (`POD_UID`) to the runner name.
+    ///
+    /// An existing VMI with the same name is deleted first.
+    PodUid,
+}
+
+/// How the VM is started.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum StartMode {
+    /// Create a VMI from the VM template and delete it when done.
+    Ephemeral,
+
+    /// Start the stopped VM and stop it when done.
+    ///
+    /// The VMI is created by KubeVirt and named after the VM.
+    Vm,
+
+    /// Create a VM from the VM template and delete it when done.
+    ///
+    /// The VM uses the `RerunOnFailure` run strategy, and the VMI
+    /// created by KubeVirt is named after it.
+    CreateVm,
+}
+
+/// A Secret or ConfigMap to mount in the guest.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mount {
+    /// Whether it's a Secret or ConfigMap.
+    pub kind: MountKind,
+
+    /// The name of the Secret or ConfigMap.
+    pub name: String,
+
+    /// Where the guest should mount it.
+    pub path: String,
+}
+
+/// The kind of a [`Mount`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MountKind {
+    Secret,
+    ConfigMap,
+}
+
+impl Mount {
+    /// Returns the name of the volume, which is also the virtiofs tag.
+    pub(crate) fn volume_name(&self) -> String {
+        // Volume names can't contain dots
+        self.name.replace('.', "-")
+    }
+}
+
+/// How runner info is passed to the VM.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum RunnerInfoMode {
+    /// Through an annotation on the VMI exposed as a `downwardAPI` volume.
+    #[value(name = "downwardAPI")]
+    DownwardApi,
+
+    /// Through a short-lived Secret exposed as a `secret` volume.
+    ///
+    /// This keeps sensitive information out of the VMI object.
+    Secret,
+}
+
+/// The options of the launcher.
+///
+/// The fields are the command line flags. Use [`Opts::default`] to
+/// get their defaults without reading the environment.
+#[derive(Parser, Debug, Clone)]
+#[clap(after_help = EXIT_CODES_HELP)]
+pub struct Opts {
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+
+    /// A YAML config file to take defaults from.
+    ///
+    /// The keys are the long names of the options (e.g., `vm-template`).
+    /// Environment variables and flags take precedence over the file.
+    /// If unspecified, `/etc/kubevirt-actions-runner/config.yaml` is
+    /// loaded if it exists.
+    #[clap(long, env = "KUBEVIRT_RUNNER_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// The namespace to operate in.
+    ///
+    /// Defaults to `POD_NAMESPACE`, then the namespace of the service
+    /// account if run in-cluster, then the namespace of the current
+    /// kubeconfig context.
+    #[clap(short = 'n', long)]
+    pub namespace: Option<String>,
+
+    /// The format of log output.
+    ///
+    /// Every line includes a random `run_id` as well as the name
+    /// and namespace of the VMI as structured fields.
+    #[clap(long, default_value = "text", env = "LOG_FORMAT")]
+    pub log_format: LogFormat,
+
+    /// An OTLP endpoint to export traces to, like `http://localhost:4317`.
+    ///
+    /// The spans cover fetching the template, creating the VMI and
+    /// watching it.
+    #[clap(long, env = "OTEL_EXPORTER_OTLP_ENDPOINT")]
+    pub otlp_endpoint: Option<String>,
+
+    /// How long to wait before exiting after an error.
+    ///
+    /// This makes it easier to get logs, since the controller
+    /// deletes the pod immediately. Set to `0` to exit right away.
+    /// `RUNNER_ERROR_HOLD_SECS` is also accepted. Defaults to 10
+    /// seconds.
+    #[clap(long, visible_alias = "error-hold", env = "ERROR_LINGER", value_parser = parse_duration)]
+    pub error_linger: Option<Duration>,
+
+    /// Also wait for `error_linger` before exiting successfully.
+    ///
+    /// This helps when debugging the teardown.
+    #[clap(long, env = "RUNNER_SUCCESS_HOLD")]
+    pub success_hold: bool,
+
+    /// The name of the runner.
+    #[clap(long, default_value = "runner", env = "RUNNER_NAME")]
+    pub name: String,
+
+    /// Use the name of the runner pod (`POD_NAME`) as the runner and VMI name.
+    ///
+    /// This overrides `name`. Pod names that aren't valid DNS
+    /// labels are shortened, with a hash to keep them unique.
+    #[clap(long, env = "KUBEVIRT_NAME_FROM_POD")]
+    pub name_from_pod: bool,
+
+    /// The opaque JIT runner config.
+    ///
+    /// If this is specified, other GitHub API configs except `name` are ignored.
+    #[clap(long, env = "ACTIONS_RUNNER_INPUT_JITCONFIG")]
+    pub jitconfig: Option<String>,
+
+    /// The runner registration token.
+    #[clap(long, env = "RUNNER_TOKEN")]
+    pub token: Option<String>,
+
+    /// A file containing the runner registration token.
+    ///
+    /// This is an alternative to `token` that avoids exposing the
+    /// token in the process environment. If neither is specified,
+    /// the token is read from
+    /// `/var/run/secrets/kubevirt-actions-runner/token` if it exists.
+    #[clap(long, env = "RUNNER_TOKEN_FILE")]
+    pub token_file: Option<PathBuf>,
+
+    /// The URL of an organization or repo to register the runner in.
+    ///
+    /// If specified, this is used as-is. Otherwise, it's built from
+    /// `runner_scope` and `runner_target` if they are specified, or
+    /// auto-detected from the following environment variables:
+    ///
+    /// - GITHUB_URL or GITHUB_SERVER_URL
+    /// - RUNNER_ORG (org)
+    /// - RUNNER_REPO (org/repo)
+    #[clap(long)]
+    pub url: Option<String>,
+
+    /// What to register the runner to.
+    ///
+    /// If specified along with `runner_target`, the URL is built
+    /// from them instead of RUNNER_ORG and RUNNER_REPO.
+    #[clap(long, env = "RUNNER_SCOPE", requires = "runner_target")]
+    pub runner_scope: Option<RunnerScope>,
+
+    /// The repository (`owner/repo`), organization or enterprise slug
+    /// to register the runner to, depending on `runner_scope`.
+    #[clap(long, env = "RUNNER_TARGET", requires = "runner_scope")]
+    pub runner_target: Option<String>,
+
+    /// Whether the runner should be ephemeral or not.
+    #[clap(long, env = "RUNNER_EPHEMERAL")]
+    pub ephemeral: bool,
+
+    /// Runner groups to attach to the runner, separated by commas.
+    ///
+    /// Spaces around them as well as empty and duplicate entries are removed.
+    /// They are appended to the groups in the `li.zhaofeng.kubevirt-actions-runner/default-groups`
+    /// annotation of the VM template, if any.
+    #[clap(long, default_value = "", env = "RUNNER_GROUPS")]
+    pub groups: String,
+
+    /// Labels to attach to the runner, separated by commas.
+    ///
+    /// Spaces around them as well as empty and duplicate entries are removed.
+    /// They are appended to the labels in the `li.zhaofeng.kubevirt-actions-runner/default-labels`
+    /// annotation of the VM template, if any.
+    #[clap(long, default_value = "", env = "RUNNER_LABELS")]
+    pub labels: String,
+
+    /// The HTTP proxy for the runner in the VM.
+    ///
+    /// If unspecified, `http_proxy` from our environment is used.
+    #[clap(long, env = "HTTP_PROXY")]
+    pub http_proxy: Option<String>,
+
+    /// The HTTPS proxy for the runner in the VM.
+    ///
+    /// If unspecified, `https_proxy` from our environment is used.
+    #[clap(long, env = "HTTPS_PROXY")]
+    pub https_proxy: Option<String>,
+
+    /// Hosts the runner in the VM shouldn't use the proxy for.
+    ///
+    /// If unspecified, `no_proxy` from our environment is used.
+    #[clap(long, env = "NO_PROXY")]
+    pub no_proxy: Option<String>,
+
+    /// The VirtualMachine resource to use as the template.
+    ///
+    /// Either this or `vm_template_selector` is required. This can be
+    /// a comma-separated list of templates to fall back to in order
+    /// if the VMI cannot be scheduled within the startup timeout.
+    #[clap(
+        long,
+        env = "KUBEVIRT_VM_TEMPLATE",
+        conflicts_with = "vm_template_selector"
+    )]
+    pub vm_template: Option<String>,
+
+    /// A label selector matching the VirtualMachine to use as the template.
+    ///
+    /// This is an alternative to `vm_template`. Exactly one
+    /// VirtualMachine must match.
+    #[clap(long, env = "KUBEVIRT_VM_TEMPLATE_SELECTOR")]
+    pub vm_template_selector: Option<String>,
+
+    /// The namespace of the VirtualMachine template.
+    ///
+    /// Defaults to the namespace we operate in. The VMI is always
+    /// created in the namespace we operate in.
+    #[clap(long, env = "KUBEVIRT_VM_TEMPLATE_NAMESPACE")]
+    pub vm_template_namespace: Option<String>,
+
+    /// How to start the VM.
+    ///
+    /// With `vm`, `vm_template` is the VirtualMachine to start. Its
+    /// template is patched with the runner info, and it's stopped
+    /// instead of having its VMI deleted once the job is done.
+    ///
+    /// With `create-vm`, a VirtualMachine is created instead of a bare
+    /// VMI, so that it can also be stopped through the VM. It's
+    /// deleted once the job is done.
+    #[clap(long, default_value = "ephemeral", env = "KUBEVIRT_START_MODE")]
+    pub start_mode: StartMode,
+
+    /// How long to wait for the VM template to appear.
+    ///
+    /// This helps when the template is created at the same time as
+    /// the runners, e.g., by a GitOps tool. By default, we fail
+    /// right away if it doesn't exist.
+    #[clap(long, default_value = "0", env = "KUBEVIRT_TEMPLATE_WAIT", value_parser = parse_duration)]
+    pub template_wait: Duration,
+
+    /// Labels to copy from the runner pod to the VMI.
+    ///
+    /// This is a comma-separated list of label keys and requires
+    /// `POD_NAME` to be set. Labels from the template take
+    /// precedence unless `--inherit-labels-override` is passed.
+    #[clap(long, env = "KUBEVIRT_INHERIT_LABELS", value_delimiter = ',')]
+    pub inherit_labels: Vec<String>,
+
+    /// Let inherited pod labels override labels from the template.
+    #[clap(long, env = "KUBEVIRT_INHERIT_LABELS_OVERRIDE")]
+    pub inherit_labels_override: bool,
+
+    /// An extra label to add to the VMI.
+    ///
+    /// The value can reference environment variables like
+    /// `${GITHUB_RUN_ID}`, or be `$ENV:NAME` to take the whole value
+    /// from an environment variable. Labels from the template take
+    /// precedence unless `--vmi-metadata-override` is passed.
+    #[clap(long = "vmi-label", value_name = "KEY=VALUE", value_parser = parse_metadata)]
+    pub vmi_labels: Vec<(String, String)>,
+
+    /// An extra annotation to add to the VMI.
+    ///
+    /// The value can reference environment variables like
+    /// `${GITHUB_RUN_ID}`, or be `$ENV:NAME` to take the whole value
+    /// from an environment variable. Annotations from the template
+    /// take precedence unless `--vmi-metadata-override` is passed.
+    #[clap(
+        long = "vmi-annotation",
+        visible_alias = "annotation",
+        value_name = "KEY=VALUE",
+        value_parser = parse_metadata
+    )]
+    pub vmi_annotations: Vec<(String, String)>,
+
+    /// Let extra labels and annotations override those from the template.
+    #[clap(long, env = "KUBEVIRT_VMI_METADATA_OVERRIDE")]
+    pub vmi_metadata_override: bool,
+
+    /// Annotate the VMI with the GitHub workflow context.
+    ///
+    /// `GITHUB_REPOSITORY`, `GITHUB_RUN_ID`, `GITHUB_WORKFLOW` and `GITHUB_JOB`
+    /// are stored in `li.zhaofeng.kubevirt-actions-runner/github-repository` and
+    /// so on. Variables that aren't set are skipped. ARC doesn't set them in
+    /// the runner pod, so they must be added to the pod spec.
+    #[clap(long, env = "KUBEVIRT_ANNOTATE_GITHUB_CONTEXT")]
+    pub annotate_github_context: bool,
+
+    /// Set a variable to substitute in the VM template.
+    ///
+    /// Occurrences of `${KEY}` in the template are replaced with the
+    /// value, and `$${` can be used for a literal `${`. Referencing an
+    /// undefined variable is an error. The template is used as-is
+    /// unless this or `set_from_env` is specified.
+    #[clap(long = "set", value_name = "KEY=VALUE", value_parser = parse_var)]
+    pub vars: Vec<(String, String)>,
+
+    /// Substitute environment variables in the VM template.
+    ///
+    /// Variables set with `--set` take precedence.
+    #[clap(long, env = "KUBEVIRT_SET_FROM_ENV")]
+    pub set_from_env: bool,
+
+    /// How long to wait for the VMI to reach `Running`.
+    ///
+    /// Accepts a number of seconds or a duration with a unit
+    /// suffix (`s`, `m`, `h`). Once the VMI is running, we wait
+    /// for it to terminate indefinitely.
+    #[clap(long, default_value = "300s", env = "KUBEVIRT_STARTUP_TIMEOUT", value_parser = parse_duration)]
+    pub startup_timeout: Duration,
+
+    /// How long to wait for the VMI to reach `Running` if the virt-launcher
+    /// pod is still pulling images.
+    ///
+    /// When `startup_timeout` expires, we keep waiting as long as some
+    /// containers of the virt-launcher pod don't have their images yet,
+    /// up to this long since the VMI was created. This requires `list`
+    /// on `pods`.
+    #[clap(long, env = "KUBEVIRT_IMAGE_PULL_TIMEOUT", value_parser = parse_duration)]
+    pub image_pull_timeout: Option<Duration>,
+
+    /// How long the VMI may run before it's deleted.
+    ///
+    /// This protects against jobs that hang. The time is counted
+    /// from when the VMI reaches `Running`, and the guest is asked to
+    /// shut down gracefully once it's exceeded. `RUNNER_MAX_RUNTIME`
+    /// is also accepted for compatibility.
+    #[clap(long, env = "KUBEVIRT_MAX_RUNTIME", value_parser = parse_duration)]
+    pub max_runtime: Option<Duration>,
+
+    /// A GitHub token to check that the runner has registered with.
+    ///
+    /// Once the VMI is running, we poll the GitHub API until a runner
+    /// with our name is online. If it doesn't show up within
+    /// `registration_timeout`, the VMI is deleted. The token needs to
+    /// be able to list self-hosted runners. This is skipped for JIT
+    /// runners.
+    #[clap(long, env = "GITHUB_PAT")]
+    pub github_pat: Option<String>,
+
+    /// The URL of the GitHub API.
+    ///
+    /// By default, this is derived from the runner URL.
+    #[clap(long, env = "GITHUB_API_URL")]
+    pub github_api_url: Option<String>,
+
+    /// How long to wait for the runner to register with GitHub.
+    #[clap(long, default_value = "10m", env = "KUBEVIRT_REGISTRATION_TIMEOUT", value_parser = parse_duration)]
+    pub registration_timeout: Duration,
+
+    /// Wait for the guest agent to connect after the VMI is running.
+    ///
+    /// The VMI reaching `Running` doesn't mean that the runner inside
+    /// has started. With this, we also watch for the `AgentConnected`
+    /// condition and warn if it doesn't appear within
+    /// `guest_agent_timeout`.
+    #[clap(long, env = "KUBEVIRT_WAIT_GUEST_AGENT")]
+    pub wait_guest_agent: bool,
+
+    /// How long to wait for the guest agent to connect.
+    #[clap(long, default_value = "300s", env = "KUBEVIRT_GUEST_AGENT_TIMEOUT", value_parser = parse_duration)]
+    pub guest_agent_timeout: Duration,
+
+    /// Wait for the VMI to have this annotation after it is running.
+    ///
+    /// The annotation should be set by the guest (e.g., from a startup
+    /// script with access to the Kubernetes API) when the runner is
+    /// ready. Until then, the VMI isn't considered ready. If it doesn't
+    /// appear within `ready_timeout`, the VMI is considered ready anyway.
+    #[clap(long, env = "KUBEVIRT_READY_ANNOTATION")]
+    pub ready_annotation: Option<String>,
+
+    /// How long to wait for the ready annotation to appear.
+    #[clap(long, default_value = "300s", env = "KUBEVIRT_READY_TIMEOUT", value_parser = parse_duration)]
+    pub ready_timeout: Duration,
+
+    /// How many consecutive errors to tolerate while watching the VMI.
+    #[clap(long, default_value = "5", env = "KUBEVIRT_MAX_WATCH_ERRORS")]
+    pub max_watch_errors: u32,
+
+    /// The name of the runner pod.
+    ///
+    /// This should be passed via the downward API. It's required
+    /// with `name_from_pod`.
+    #[clap(long, env = "POD_NAME", required_if_eq("name_from_pod", "true"))]
+    pub pod_name: Option<String>,
+
+    /// The UID of the runner pod.
+    ///
+    /// This should be passed via the downward API. If set along
+    /// with `pod_name`, the VMI will be owned by the runner pod
+    /// and garbage-collected if we die without cleaning up.
+    #[clap(long, env = "POD_UID")]
+    pub pod_uid: Option<String>,
+
+    /// Print the VMI that would be created and exit.
+    ///
+    /// The Secrets to be created along with it (the runner info
+    /// Secret with `--runner-info-mode secret`, and the SSH keys)
+    /// are printed as well. The template is still fetched, but nothing
+    /// in the cluster is created or deleted.
+    ///
+    /// Note that the output contains the runner credentials.
+    #[clap(long)]
+    pub dry_run: bool,
+
+    /// A label selector matching VMIs of this runner set.
+    ///
+    /// If set, VMIs matching the selector whose runner pod (recorded
+    /// in the `li.zhaofeng.kubevirt-actions-runner/owner-pod` label)
+    /// no longer exists are deleted at startup.
+    #[clap(long, env = "KUBEVIRT_GC_LABEL_SELECTOR")]
+    pub gc_label_selector: Option<String>,
+
+    /// Don't publish Kubernetes Events for the VMI lifecycle.
+    ///
+    /// If `pod_name` is set, events are published on the runner pod.
+    /// Otherwise, they are published on the VMI.
+    #[clap(long)]
+    pub no_events: bool,
+
+    /// The name of the volume to inject runner info into.
+    ///
+    /// If the template already has a volume with this name,
+    /// it will be replaced.
+    #[clap(long, visible_alias = "runner-info-volume", default_value = RUNNER_INFO_VOLUME)]
+    pub runner_info_volume_name: String,
+
+    /// The path of the runner info file inside the volume.
+    ///
+    /// This must be a relative path.
+    #[clap(long, default_value = RUNNER_INFO_PATH, value_parser = parse_runner_info_path)]
+    pub runner_info_path: String,
+
+    /// The annotation to store runner info in.
+    ///
+    /// This is used with the `downwardAPI` mode, and to recognize
+    /// VMIs created by us when collecting garbage.
+    #[clap(long, default_value = RUNNER_INFO_ANNOTATION, value_parser = parse_metadata_key)]
+    pub runner_info_annotation: String,
+
+    /// How to pass runner info to the VM.
+    #[clap(long, default_value = "downwardAPI", env = "RUNNER_INFO_MODE")]
+    pub runner_info_mode: RunnerInfoMode,
+
+    /// A cloud-init user data template to pass runner info with.
+    ///
+    /// `{{name}}`, `{{token}}`, `{{url}}`, `{{ephemeral}}`,
+    /// `{{groups}}`, `{{labels}}`, `{{jitconfig}}` and the proxy
+    /// settings (e.g., `{{https_proxy}}`) are replaced with the
+    /// runner info, and the result is set as the user data
+    /// of the `cloud_init_volume_name` volume. The runner info volume
+    /// is still added. Unless `runner_info_mode` is `secret`, the
+    /// user data, including the token, is readable in the VMI spec.
+    #[clap(long, env = "KUBEVIRT_CLOUD_INIT_TEMPLATE")]
+    pub cloud_init_template: Option<PathBuf>,
+
+    /// Cloud-init user data to merge into the VM template.
+    ///
+    /// The same placeholders as `cloud_init_template` are replaced,
+    /// in the parsed strings with `#cloud-config`. If the template
+    /// has a cloud-init volume with `#cloud-config` user data, the
+    /// two are merged, with lists like `runcmd` and `write_files`
+    /// concatenated. Otherwise, a `cloudInitNoCloud` volume and disk
+    /// named `cloud_init_volume_name` are added.
+    #[clap(
+        long,
+        env = "KUBEVIRT_CLOUD_INIT_USER_DATA",
+        conflicts_with = "cloud_init_template"
+    )]
+    pub cloud_init_user_data: Option<PathBuf>,
+
+    /// The name of the `cloudInitNoCloud` volume to set the user data of.
+    ///
+    /// If the template already has a volume with this name, its
+    /// user data will be replaced.
+    #[clap(long, default_value = CLOUD_INIT_VOLUME)]
+    pub cloud_init_volume_name: String,
+
+    /// Fail if the VM template doesn't use the runner info volume.
+    ///
+    /// Otherwise, only a warning is logged.
+    #[clap(long, env = "KUBEVIRT_STRICT_TEMPLATE")]
+    pub strict_template: bool,
+
+    /// The number of CPU cores of the VMI.
+    ///
+    /// This overrides `spec.domain.cpu.cores` in the template.
+    #[clap(long, visible_alias = "cpu", env = "KUBEVIRT_VMI_CPU", value_parser = clap::value_parser!(u32).range(1..))]
+    pub vmi_cpu: Option<u32>,
+
+    /// The CPU model of the VMI, like `host-passthrough`.
+    ///
+    /// This overrides `spec.domain.cpu.model` in the template.
+    #[clap(long, visible_alias = "cpu-model", env = "KUBEVIRT_VMI_CPU_MODEL")]
+    pub vmi_cpu_model: Option<String>,
+
+    /// The memory of the VMI, like `4Gi`.
+    ///
+    /// This overrides `spec.domain.resources.requests.memory` in the
+    /// template, as well as `spec.domain.resources.limits.memory` and
+    /// `spec.domain.memory.guest` if they are set.
+    #[clap(long, visible_alias = "memory", env = "KUBEVIRT_VMI_MEMORY", value_parser = parse_quantity)]
+    pub vmi_memory: Option<String>,
+
+    /// A Multus network to attach the VMI to, in addition to the
+    /// networks in the template (repeatable).
+    ///
+    /// This is the name of a NetworkAttachmentDefinition, optionally
+    /// prefixed with its namespace (`namespace/name`). A bridge
+    /// interface is added for each network.
+    #[clap(
+        long = "network-attachment",
+        env = "KUBEVIRT_NETWORK_ATTACHMENTS",
+        value_delimiter = ','
+    )]
+    pub network_attachments: Vec<String>,
+
+    /// A Secret or ConfigMap to mount in the guest (repeatable).
+    ///
+    /// This is `secret:NAME:PATH` or `configmap:NAME:PATH`. It's added
+    /// as a volume with a virtiofs filesystem, tagged with the name
+    /// of the volume, and `PATH` is passed in the runner info (under
+    /// `mounts`) for the guest to mount it at.
+    #[clap(
+        long = "mount",
+        env = "KUBEVIRT_MOUNTS",
+        value_delimiter = ',',
+        value_parser = parse_mount
+    )]
+    pub mounts: Vec<Mount>,
+
+    /// An environment variable to pass to the runner in the VM (repeatable).
+    ///
+    /// This is `KEY=VALUE`, and it's passed in the runner info (under
+    /// `env`) for the guest to export before starting the runner.
+    #[clap(long = "runner-env", value_name = "KEY=VALUE", value_parser = parse_env_var)]
+    pub runner_env: Vec<(String, String)>,
+
+    /// The PriorityClass of the VMI, overriding the one in the template.
+    #[clap(long, env = "KUBEVIRT_PRIORITY_CLASS")]
+    pub priority_class: Option<String>,
+
+    /// An SSH public key to authorize in the guest (repeatable).
+    ///
+    /// This is for break-glass access to misbehaving runners. The keys
+    /// are propagated by the guest agent through KubeVirt's
+    /// `accessCredentials`, so the guest agent must be installed.
+    /// In the environment variable, keys are separated by newlines.
+    #[clap(
+        long = "ssh-authorized-key",
+        env = "SSH_AUTHORIZED_KEYS",
+        value_delimiter = '\n'
+    )]
+    pub ssh_authorized_keys: Vec<String>,
+
+    /// The guest users to authorize the SSH keys for.
+    #[clap(
+        long = "ssh-user",
+        default_value = "root",
+        env = "KUBEVIRT_SSH_USERS",
+        value_delimiter = ','
+    )]
+    pub ssh_users: Vec<String>,
+
+    /// How to name the VMI.
+    #[clap(long, default_value = "fixed", env = "KUBEVIRT_VMI_NAME_STRATEGY")]
+    pub vmi_name_strategy: VmiNameStrategy,
+
+    /// The name of the VMI, instead of the runner name.
+    ///
+    /// This is still subject to `vmi_name_strategy`, so pass
+    /// `--vmi-name-strategy fixed` (the default) to use it as-is.
+    #[clap(long, env = "KUBEVIRT_VMI_NAME")]
+    pub vmi_name: Option<String>,
+
+    /// How many times to retry creating the VMI on transient errors.
+    ///
+    /// Server errors, timeouts, conflicts and connection errors
+    /// are retried with jittered exponential backoff. On conflicts,
+    /// the existing VMI is deleted first.
+    #[clap(long, default_value = "3", env = "KUBEVIRT_CREATE_RETRIES")]
+    pub create_retries: u32,
+
+    /// Delete an existing VMI with the same name that was left over by a previous runner.
+    ///
+    /// Only VMIs created by us (labeled with `app.kubernetes.io/managed-by:
+    /// kubevirt-actions-runner`, or carrying the runner info annotation or
+    /// the owner pod label) are deleted. If disabled, or if the VMI belongs
+    /// to something else, we fail instead.
+    #[clap(
+        long,
+        default_value_t = true,
+        action = clap::ArgAction::Set,
+        env = "KUBEVIRT_RECLAIM_EXISTING"
+    )]
+    pub reclaim_existing: bool,
+
+    /// Only take over an existing VMI that was created less than this long ago.
+    ///
+    /// Older VMIs are treated like any other VMI left over by a
+    /// previous runner.
+    #[clap(long, default_value = "24h", env = "KUBEVIRT_ADOPT_MAX_AGE", value_parser = parse_duration)]
+    pub adopt_max_age: Duration,
+
+    /// How many times to recreate the VMI if it fails before reaching `Running`.
+    ///
+    /// Failures after the VMI has reached `Running` are likely caused
+    /// by the job and are never retried.
+    #[clap(long, default_value = "0", env = "KUBEVIRT_BOOT_RETRIES")]
+    pub boot_retries: u32,
+
+    /// How many times to retry discovering KubeVirt on transient errors.
+    ///
+    /// This tolerates the API server being briefly unreachable when we
+    /// start, e.g., during a cluster cold start. Attempts are retried
+    /// with exponential backoff starting at 1 second, so the default
+    /// waits for about 30 seconds. Authorization errors are not retried.
+    #[clap(long, default_value = "5", env = "KUBEVIRT_SETUP_RETRIES")]
+    pub setup_retries: u32,
+
+    /// How long to wait for the guest to shut down gracefully.
+    ///
+    /// When the VMI is deleted, KubeVirt asks the guest to shut down
+    /// via ACPI. If the VMI is still around after this period, we
+    /// force it off.
+    #[clap(long, default_value = "30s", env = "KUBEVIRT_SHUTDOWN_GRACE", value_parser = parse_duration)]
+    pub shutdown_grace: Duration,
+
+    /// Don't delete the VMI if it fails, for debugging.
+    ///
+    /// This applies when the VMI fails, doesn't start in time, or
+    /// we stop watching it unexpectedly. The exit code is the same as
+    /// without it.
+    #[clap(long, visible_alias = "keep-vmi", env = "KUBEVIRT_KEEP_VMI_ON_FAILURE")]
+    pub keep_vmi_on_failure: bool,
+
+    /// Wait for the VMI object to be gone after stopping the VM.
+    ///
+    /// This makes the lifetime of the runner pod cover that of the
+    /// VMI in the `vm` and `create-vm` start modes. If it's still
+    /// around after `deletion_timeout`, we only log a warning. VMIs
+    /// we delete ourselves are always waited for.
+    #[clap(long, env = "KUBEVIRT_WAIT_FOR_DELETION")]
+    pub wait_for_deletion: bool,
+
+    /// How long to wait for the VMI to be gone after deleting it.
+    ///
+    /// This bounds waiting for the finalizers of the VMI, so a stuck
+    /// finalizer doesn't keep us around forever. On timeout, we log
+    /// a warning and exit anyway.
+    #[clap(long, visible_alias = "delete-timeout", default_value = "60s", env = "KUBEVIRT_DELETION_TIMEOUT", value_parser = parse_duration)]
+    pub deletion_timeout: Duration,
+
+    /// Force-delete the VMI if it isn't gone within `deletion_timeout`.
+    #[clap(long, env = "KUBEVIRT_FORCE_DELETE")]
+    pub force_delete: bool,
+
+    /// A command to run once the VMI has reached a terminal outcome.
+    ///
+    /// The command is run with `sh -c` after the VMI is deleted, with
+    /// `KUBEVIRT_OUTCOME`, `KUBEVIRT_VMI_NAME` and `KUBEVIRT_VMI_NAMESPACE`
+    /// set. It's also run if we fail early, with `KUBEVIRT_OUTCOME=Error`.
+    /// If it fails, a warning is logged. This requires a shell in the image.
+    #[clap(long, env = "KUBEVIRT_ON_COMPLETE")]
+    pub on_complete: Option<String>,
+
+    /// Exit with an error if the on-complete command fails.
+    #[clap(long, env = "KUBEVIRT_ON_COMPLETE_STRICT")]
+    pub on_complete_strict: bool,
+
+    /// Relay the serial console of the VMI to our logs.
+    ///
+    /// The console is connected once the VMI is running.
+    #[clap(long)]
+    pub stream_console: bool,
+
+    /// How many lines of the serial console to log if the VMI fails.
+    ///
+    /// The console is read in the background once the VMI is running.
+    /// If we cannot connect to it, a warning is logged and nothing
+    /// is captured.
+    #[clap(long, env = "KUBEVIRT_CONSOLE_TAIL")]
+    pub console_tail: Option<usize>,
+
+    /// A directory to capture diagnostics of failed VMIs to.
+    ///
+    /// If the VMI fails, doesn't start in time or we stop watching it
+    /// unexpectedly, the VMI, its virt-launcher pods and their events
+    /// are written to a subdirectory named after the VMI before it's
+    /// deleted. With `--stream-console` or `--console-tail`, the last
+    /// lines of the serial console are included as well.
+    #[clap(long, env = "KUBEVIRT_DIAGNOSTICS_DIR")]
+    pub diagnostics_dir: Option<PathBuf>,
+
+    /// The address to serve Prometheus metrics on.
+    ///
+    /// Metrics are served at `/metrics`.
+    #[clap(long, env = "METRICS_ADDR")]
+    pub metrics_addr: Option<SocketAddr>,
+
+    /// A Prometheus Pushgateway to push metrics to before exiting.
+    ///
+    /// Runner pods are short-lived and may be gone before
+    /// Prometheus gets to scrape them.
+    #[clap(long, env = "METRICS_PUSHGATEWAY")]
+    pub metrics_pushgateway: Option<String>,
+
+    /// The address to serve health checks on.
+    ///
+    /// `/healthz` succeeds unless watching the VMI is failing or the
+    /// VMI has failed, and `/readyz` succeeds while the VMI is running.
+    #[clap(long, env = "HEALTH_ADDR")]
+    pub health_addr: Option<SocketAddr>,
+
+    /// How long we may not hear about the VMI before `/healthz` fails.
+    #[clap(long, default_value = "10m", env = "HEALTH_STALL_TIMEOUT", value_parser = parse_duration)]
+    pub health_stall_timeout: Duration,
+
+    /// A file to write information about the created VMI to.
+    ///
+    /// This is a JSON document with the name, namespace, UID and
+    /// creation timestamp of the VMI, written once it's created.
+    /// The guest IP (`guestIP`) is added once it's known.
+    #[clap(long, env = "KUBEVIRT_STATUS_FILE")]
+    pub status_file: Option<PathBuf>,
+
+    /// A file to write a summary of the run to.
+    ///
+    /// This is a JSON document with the name and UID of the VMI, its
+    /// last phase, the outcome, how long startup and the whole run
+    /// took, and the error if any. It's written before we exit, even
+    /// if we exit with an error.
+    #[clap(long, env = "KUBEVIRT_SUMMARY_FILE")]
+    pub summary_file: Option<PathBuf>,
+
+    /// The environment variables the runner URL, proxy settings,
+    /// JIT runner inputs and template variables are taken from.
+    ///
+    /// This is the process environment with [`Opts::from_cli`], and
+    /// empty by default.
+    #[clap(skip)]
+    pub env: BTreeMap<String, String>,
+}
+
+/// A subcommand.
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Delete runner VMIs whose runner pod is gone.
+    ///
+    /// Without `--confirm`, the VMIs are only printed.
+    Gc(GcOpts),
+
+    /// Print the runner info that would be passed to the VM.
+    ///
+    /// Secrets are redacted unless `--show-secrets` is passed.
+    PrintRunnerInfo(PrintRunnerInfoOpts),
+}
+
+/// The options of `print-runner-info`.
+#[derive(Parser, Debug, Clone)]
+pub struct PrintRunnerInfoOpts {
+    /// Print secrets like the token or JIT config and proxy credentials as-is.
+    #[clap(long)]
+    pub show_secrets: bool,
+}
+
+/// The options of `gc`.
+#[derive(Parser, Debug, Clone)]
+pub struct GcOpts {
+    /// Only delete VMIs older than this.
+    #[clap(long, default_value = "1h", value_parser = parse_duration)]
+    pub older_than: Duration,
+
+    /// Actually delete the VMIs.
+    #[clap(long)]
+    pub confirm: bool,
+}
+
+impl Opts {
+    /// Parses options from the command line, environment and config file.
+    ///
+    /// This exits on `--help` and invalid flags like clap does.
+    pub fn from_cli() -> AnyResult<Self> {
+        let mut opts: Self = config::parse()?;
+        // `env::vars` panics on variables that aren't valid UTF-8
+        opts.env = env::vars_os()
+            .filter_map(|(key, value)| Some((key.into_string().ok()?, value.into_string().ok()?)))
+            .collect();
+        Ok(opts)
+    }
+
+    /// Checks options that depend on each other.
+    pub(crate) fn validate(&self) -> AnyResult<()> {
+        if self.vm_template.is_none() && self.vm_template_selector.is_none() {
+            return Err(anyhow!(
+                "Either --vm-template or --vm-template-selector must be specified"
+            ));
+        }
+
+        if self
+            .vmi_annotations
+            .iter()
+            .any(|(key, _)| key == &self.runner_info_annotation)
+        {
+            return Err(anyhow!(
+                "{} is reserved for runner info",
+                self.runner_info_annotation
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Applies `name_from_pod`.
+    pub(crate) fn resolve_name(&mut self) -> AnyResult<()> {
+        if self.name_from_pod {
+            let pod_name = self
+                .pod_name
+                .as_deref()
+                .ok_or_else(|| anyhow!("POD_NAME must be set to use --name-from-pod"))?;
+            self.name = dns_label(pod_name);
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for Opts {
+    fn default() -> Self {
+        config::defaults().expect("The defaults must be valid")
+    }
+}
+
+/// Parses a duration like `300`, `30s`, `5m` or `1h`.
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let (number, multiplier) = match s.char_indices().last() {
+        Some((i, 's')) => (&s[..i], 1),
+        Some((i, 'm')) => (&s[..i], 60),
+        Some((i, 'h')) => (&s[..i], 60 * 60),
+        _ => (s, 1),
+    };
+
+    let secs = number
+        .parse::<u64>()
+        .map_err(|_| format!("Invalid duration: {}", s))?
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("Duration is too long: {}", s))?;
+
+    Ok(Duration::from_secs(secs))
+}
+
+/// Parses a Kubernetes quantity like `512Mi` or `4G`.
+fn parse_quantity(s: &str) -> Result<String, String> {
+    const SUFFIXES: &[&str] = &[
+        "Ki", "Mi", "Gi", "Ti", "Pi", "Ei", "k", "M", "G", "T", "P", "E", "",
+    ];
+
+    let s = s.trim();
+    let number = SUFFIXES
+        .iter()
+        .find_map(|suffix| s.strip_suffix(suffix))
+        .unwrap_or(s);
+
+    let valid = !number.is_empty()
+        && number.chars().all(|c| c.is_ascii_digit() || c == '.')
+        && number.chars().filter(|c| *c == '.').count() <= 1
+        && number.chars().any(|c| c.is_ascii_digit() && c != '0');
+
+    if valid {
+        Ok(s.to_string())
+    } else {
+        Err(format!("Invalid quantity: {}", s))
+    }
+}
+
+/// Parses a template variable like `KEY=VALUE`.
+fn parse_var(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!("Invalid variable (expected KEY=VALUE): {}", s)),
+    }
+}
+
+/// Parses an environment variable like `KEY=VALUE`.
+///
+/// The key must be a valid shell variable name.
+fn parse_env_var(s: &str) -> Result<(String, String), String> {
+    let (key, value) = parse_var(s)?;
+
+    let valid = !key.starts_with(|c: char| c.is_ascii_digit())
+        && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid {
+        return Err(format!("Invalid environment variable name: {}", key));
+    }
+
+    Ok((key, value))
+}
+
+/// Parses a label or annotation like `KEY=VALUE`.
+///
+/// The key must be a valid Kubernetes label or annotation key.
+fn parse_metadata(s: &str) -> Result<(String, String), String> {
+    let (key, value) = parse_var(s)?;
+    Ok((parse_metadata_key(&key)?, value))
+}
+
+/// Validates a Kubernetes label or annotation key.
+fn parse_metadata_key(key: &str) -> Result<String, String> {
+    let (prefix, name) = match key.split_once('/') {
+        Some((prefix, name)) => (Some(prefix), name),
+        None => (None, key),
+    };
+
+    if let Some(prefix) = prefix {
+        if !is_dns_subdomain(prefix) {
+            return Err(format!(
+                "Invalid key prefix (must be a DNS subdomain): {}",
+                key
+            ));
+        }
+    }
+
+    let valid = !name.is_empty()
+        && name.len() <= 63
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+        && name.starts_with(|c: char| c.is_ascii_alphanumeric())
+        && name.ends_with(|c: char| c.is_ascii_alphanumeric());
+
+    if !valid {
+        return Err(format!(
+            "Invalid key name (must be up to 63 alphanumeric characters, '-', '_' or '.'): {}",
+            key
+        ));
+    }
+
+    Ok(key.to_string())
+}
+
+/// Returns whether a name is a valid DNS subdomain (RFC 1123).
+///
+/// Most Kubernetes objects, including VMIs, must be named like this.
+pub(crate) fn is_dns_subdomain(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 253
+        && name.split('.').all(|part| {
+            !part.is_empty()
+                && part
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+                && !part.starts_with('-')
+                && !part.ends_with('-')
+        })
+}
+
+/// Parses a mount like `secret:NAME:PATH` or `configmap:NAME:PATH`.
+pub(crate) fn parse_mount(s: &str) -> Result<Mount, String> {
+    let invalid = || {
+        format!(
+            "Invalid mount (expected secret:NAME:PATH or configmap:NAME:PATH): {}",
+            s
+        )
+    };
+
+    let mut parts = s.splitn(3, ':');
+    let (Some(kind), Some(name), Some(path)) = (parts.next(), parts.next(), parts.next()) else {
+        return Err(invalid());
+    };
+
+    let kind = match kind {
+        "secret" => MountKind::Secret,
+        "configmap" => MountKind::ConfigMap,
+        _ => return Err(invalid()),
+    };
+
+    if name.is_empty() || !path.starts_with('/') {
+        return Err(invalid());
+    }
+
+    Ok(Mount {
+        kind,
+        name: name.to_string(),
+        path: path.to_string(),
+    })
+}
+
+/// Validates a path inside the runner info volume.
+fn parse_runner_info_path(s: &str) -> Result<String, String> {
+    if s.is_empty() {
+        return Err("The runner info path cannot be empty".to_string());
+    }
+
+    if s.starts_with('/') {
+        return Err("The runner info path must be relative".to_string());
+    }
+
+    Ok(s.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsString;
+    use std::fs;
+    use std::iter;
+
+    use super::*;
+    use crate::random_suffix;
+
+    #[test]
+    fn test_duration() {
+        assert_eq!(Ok(Duration::from_secs(30)), parse_duration("30"));
+        assert_eq!(Ok(Duration::from_secs(30)), parse_duration("30s"));
+        assert_eq!(Ok(Duration::from_secs(10 * 60)), parse_duration("10m"));
+        assert_eq!(Ok(Duration::from_secs(2 * 60 * 60)), parse_duration("2h"));
+
+        for invalid in ["", "h", "-1s", "1d", "18446744073709551615h"] {
+            assert!(parse_duration(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_quantity() {
+        for valid in ["4Gi", "512Mi", "1.5G", "1000000"] {
+            assert_eq!(Ok(valid.to_string()), parse_quantity(valid));
+        }
+
+        for invalid in ["", "Gi", "0Gi", "4GB", "-1Gi", "1.2.3Gi"] {
+            assert!(parse_quantity(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_dns_subdomain() {
+        for valid in ["runner", "runner-ab12cd", "runner.ci.example", "0"] {
+            assert!(is_dns_subdomain(valid), "{}", valid);
+        }
+
+        for invalid in [
+            "",
+            "Runner",
+            "runner,x=y",
+            "runner=x",
+            "-runner",
+            "runner-",
+            "runner..ci",
+            &"a".repeat(254),
+        ] {
+            assert!(!is_dns_subdomain(invalid), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_metadata_key() {
+        for valid in [
+            "team=ci",
+            "checkout/commit=abc",
+            "arc.example.com/runner-set=",
+            "a.b_c-d=x",
+        ] {
+            assert!(parse_metadata(valid).is_ok(), "{}", valid);
+        }
+
+        for invalid in [
+            "=x",
+            "-team=ci",
+            "team-=ci",
+            "Example.com/team=ci",
+            "/team=ci",
+            "example.com/=ci",
+            "a/b/c=ci",
+            "team",
+        ] {
+            assert!(parse_metadata(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_reserved_annotation() {
+        let opts = |annotation: &str, runner_info_annotation: &str| Opts {
+            vm_template: Some("runner".to_string()),
+            vmi_annotations: vec![(annotation.to_string(), "x".to_string())],
+            runner_info_annotation: runner_info_annotation.to_string(),
+            ..Opts::default()
+        };
+
+        assert!(opts(RUNNER_INFO_ANNOTATION, RUNNER_INFO_ANNOTATION)
+            .validate()
+            .is_err());
+        assert!(opts("example.com/info", "example.com/info")
+            .validate()
+            .is_err());
+        assert!(opts(RUNNER_INFO_ANNOTATION, "example.com/info")
+            .validate()
+            .is_ok());
+    }
+
+    #[test]
+    fn test_config_file_conflicts() {
+        let dir = env::temp_dir().join(format!("kubevirt-actions-runner-{}", random_suffix()));
+        fs::create_dir_all(&dir).unwrap();
+        let config_file = dir.join("config.yaml");
+        fs::write(
+            &config_file,
+            "vm-template: file-template\nstartup-timeout: 10m\n",
+        )
+        .unwrap();
+
+        let args = |extra: &[&str]| -> Vec<OsString> {
+            ["kubevirt-actions-runner", "--config"]
+                .iter()
+                .map(OsString::from)
+                .chain(iter::once(config_file.clone().into_os_string()))
+                .chain(extra.iter().map(OsString::from))
+                .collect()
+        };
+
+        let opts: Opts = config::parse_from(args(&[])).unwrap();
+        assert_eq!(Some("file-template"), opts.vm_template.as_deref());
+        assert_eq!(Duration::from_secs(600), opts.startup_timeout);
+
+        let opts: Opts =
+            config::parse_from(args(&["--vm-template-selector", "size=large"])).unwrap();
+        assert_eq!(None, opts.vm_template);
+        assert_eq!(Some("size=large"), opts.vm_template_selector.as_deref());
+        assert_eq!(Duration::from_secs(600), opts.startup_timeout);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_env_var() {
+        assert_eq!(
+            (
+                "ACTIONS_RUNNER_HOOK_JOB_STARTED".to_string(),
+                "/opt/hook.sh".to_string()
+            ),
+            parse_env_var("ACTIONS_RUNNER_HOOK_JOB_STARTED=/opt/hook.sh").unwrap()
+        );
+        assert!(parse_env_var("_A1=a=b").is_ok());
+
+        for invalid in ["=x", "1A=x", "A-B=x", "A.B=x", "A"] {
+            assert!(parse_env_var(invalid).is_err(), "{}", invalid);
+        }
+    }
+}
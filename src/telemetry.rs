@@ -0,0 +1,55 @@
+//! Logging and trace export.
+
+use anyhow::{Context, Result as AnyResult};
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{runtime, trace, Resource};
+use tracing_subscriber::{filter::LevelFilter, layer::SubscriberExt, util::SubscriberInitExt};
+
+use crate::LogFormat;
+
+/// The service name reported to the OTLP endpoint.
+const SERVICE_NAME: &str = "kubevirt-actions-runner";
+
+/// Sets up logging, and exporting spans if `otlp_endpoint` is set.
+///
+/// This must be called inside the Tokio runtime.
+pub fn init(log_format: LogFormat, otlp_endpoint: Option<&str>) -> AnyResult<()> {
+    let text = (log_format == LogFormat::Text).then(tracing_subscriber::fmt::layer);
+    let json = (log_format == LogFormat::Json).then(|| tracing_subscriber::fmt::layer().json());
+
+    let otel = match otlp_endpoint {
+        Some(endpoint) => {
+            let exporter = opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(endpoint);
+            let resource = Resource::new([KeyValue::new("service.name", SERVICE_NAME)]);
+            let tracer = opentelemetry_otlp::new_pipeline()
+                .tracing()
+                .with_exporter(exporter)
+                .with_trace_config(trace::config().with_resource(resource))
+                .install_batch(runtime::Tokio)
+                .context("Failed to set up the OTLP exporter")?;
+
+            Some(tracing_opentelemetry::layer().with_tracer(tracer))
+        }
+        None => None,
+    };
+
+    tracing_subscriber::registry()
+        .with(LevelFilter::INFO)
+        .with(text)
+        .with(json)
+        .with(otel)
+        .init();
+
+    Ok(())
+}
+
+/// Flushes spans that haven't been exported yet.
+pub async fn shutdown() {
+    // This blocks until the batch exporter is done
+    tokio::task::spawn_blocking(opentelemetry::global::shutdown_tracer_provider)
+        .await
+        .ok();
+}
@@ -0,0 +1,1452 @@
+//! Turning the VM template into the runner VMI.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result as AnyResult};
+use k8s_openapi::api::core::v1::Secret;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::OwnerReference;
+use kube::{
+    api::{Api, ListParams},
+    core::ObjectMeta,
+    ResourceExt,
+};
+use serde_json::Value;
+use tokio::time::Instant;
+use tracing::Instrument;
+
+use crate::{
+    error::RunnerError,
+    launcher::StopHandle,
+    lifecycle::Apis,
+    opts::{Mount, MountKind, Opts, RunnerInfoMode},
+    random_suffix,
+    runner_info::{merge_template_defaults, non_empty, RunnerInfo},
+    VirtualMachine, VirtualMachineInstance, VirtualMachineInstanceSpec, VirtualMachineTemplate,
+    Volume, ANNOTATIONS_SIZE_LIMIT, CLOUD_CONFIG_HEADER, CLOUD_INIT_SECRET_KEY,
+    GITHUB_CONTEXT_ANNOTATION_PREFIX, GITHUB_CONTEXT_VARS, MANAGED_BY, MANAGED_BY_LABEL,
+    OWNER_POD_LABEL, RUNNER_INFO_SECRET_KEY, SSH_KEYS_SECRET_SUFFIX, TEMPLATE_RETRY_INTERVAL,
+};
+
+/// User data to set on a cloud-init volume.
+#[derive(Debug, Clone)]
+pub(crate) struct CloudInit {
+    /// The name of the volume.
+    pub(crate) volume: String,
+
+    /// The kind of volume source, e.g., `cloudInitNoCloud`.
+    pub(crate) source: &'static str,
+
+    /// The user data.
+    pub(crate) user_data: String,
+}
+
+/// The runner VMI to create, along with the Secrets it references.
+#[derive(Debug, Clone)]
+pub(crate) struct Plan {
+    pub(crate) vmi: VirtualMachineInstance,
+
+    /// Secrets to create along with the VMI.
+    pub(crate) secrets: Vec<Secret>,
+
+    /// Whether the VM template uses `runStrategy` rather than `running`.
+    pub(crate) run_strategy: bool,
+
+    /// The runner info Secret, with `--runner-info-mode secret`.
+    pub(crate) runner_info_secret: Option<String>,
+}
+
+/// Turns the VM template into the runner VMI.
+///
+/// `opts.runner_info_mode` is switched to `Secret` if the runner info
+/// doesn't fit in the annotations.
+pub(crate) async fn prepare(
+    apis: &Apis,
+    opts: &mut Opts,
+    mut runner_info: RunnerInfo,
+    vmi_name: &str,
+    generate_name: bool,
+    stop: &StopHandle,
+) -> Result<Plan, RunnerError> {
+    let env = opts.env.clone();
+
+    let mut template = fetch_template(
+        &apis.templates,
+        &apis.template_namespace,
+        opts.vm_template.as_deref(),
+        opts.vm_template_selector.as_deref(),
+        opts.template_wait,
+        stop,
+    )
+    .instrument(tracing::info_span!("fetch_template", otel.kind = "client"))
+    .await
+    .map_err(RunnerError::Template)?;
+    let run_strategy = template.spec.run_strategy.is_some();
+
+    if let RunnerInfo::Legacy(info) = &mut runner_info {
+        let (labels, groups) = (info.labels.clone(), info.groups.clone());
+        merge_template_defaults(info, template.annotations());
+        if info.labels != labels {
+            tracing::info!("Merged default labels from the template: {}", info.labels);
+        }
+        if info.groups != groups {
+            tracing::info!("Merged default groups from the template: {}", info.groups);
+        }
+    }
+
+    if !opts.vars.is_empty() || opts.set_from_env {
+        let vars: BTreeMap<_, _> = opts.vars.iter().cloned().collect();
+        let env = opts.set_from_env.then_some(&env);
+        template.spec.template = render_template(template.spec.template, &vars, env)?;
+    }
+
+    let cloud_init = if let Some(path) = &opts.cloud_init_template {
+        let template = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read cloud-init template {}", path.display()))?;
+        let user_data = render_cloud_init(&template, &runner_info)
+            .with_context(|| format!("Failed to render cloud-init template {}", path.display()))?;
+        Some(CloudInit {
+            volume: opts.cloud_init_volume_name.clone(),
+            source: "cloudInitNoCloud",
+            user_data,
+        })
+    } else if let Some(path) = &opts.cloud_init_user_data {
+        let user_data = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read cloud-init user data {}", path.display()))?;
+        let user_data = render_user_data(&user_data, &runner_info)
+            .with_context(|| format!("Failed to render cloud-init user data {}", path.display()))?;
+        let cloud_init = merge_cloud_init(
+            &mut template.spec.template.spec,
+            &opts.cloud_init_volume_name,
+            &user_data,
+        )
+        .context("Failed to merge cloud-init user data")?;
+        Some(cloud_init)
+    } else {
+        None
+    };
+
+    // With cloud-init, the runner may not need the runner info volume at all
+    let device_volume = match &cloud_init {
+        Some(cloud_init) => &cloud_init.volume,
+        None => &opts.runner_info_volume_name,
+    };
+    if !has_device_for_volume(&template.spec.template.spec, device_volume) {
+        let message = format!(
+            "The VM template has no filesystem or disk for the {} volume - The runner won't be able to get its config. \
+            Add a device named {} to `spec.template.spec.domain.devices.filesystems` or `disks` of the VirtualMachine",
+            device_volume, device_volume
+        );
+        if opts.strict_template {
+            return Err(RunnerError::Template(anyhow!(message)));
+        }
+        tracing::warn!("{}", message);
+    }
+
+    override_resources(
+        &mut template.spec.template.spec,
+        opts.vmi_cpu,
+        opts.vmi_cpu_model.as_deref(),
+        opts.vmi_memory.as_deref(),
+    )
+    .context("Failed to override VMI resources")?;
+
+    add_network_attachments(&mut template.spec.template.spec, &opts.network_attachments)
+        .context("Failed to add network attachments")?;
+
+    add_mounts(
+        &mut template.spec.template.spec,
+        &opts.mounts,
+        &[&opts.runner_info_volume_name, &opts.cloud_init_volume_name],
+    )
+    .context("Failed to add mounts")?;
+
+    if let Some(priority_class) = &opts.priority_class {
+        tracing::info!("Using priority class {}", priority_class);
+        template.spec.template.spec.data.insert(
+            "priorityClassName".to_string(),
+            priority_class.clone().into(),
+        );
+    }
+
+    let mut vmi =
+        VirtualMachineInstance::new("vmi", &apis.vmi_resource, template.spec.template.spec);
+    vmi.metadata = template.spec.template.metadata;
+    if generate_name {
+        vmi.metadata.name = None;
+        vmi.metadata.generate_name = Some(format!("{}-", vmi_name));
+    } else {
+        vmi.metadata.name = Some(vmi_name.to_string());
+    }
+
+    if !opts.inherit_labels.is_empty() {
+        let pod_name = opts.pod_name.as_deref().ok_or_else(|| {
+            RunnerError::Config(anyhow!(
+                "POD_NAME must be set to inherit labels from the runner pod"
+            ))
+        })?;
+
+        let pod = apis
+            .pods
+            .get(pod_name)
+            .await
+            .with_context(|| format!("Failed to get runner pod {}", pod_name))?;
+
+        let labels = vmi.metadata.labels.get_or_insert_with(Default::default);
+        for key in &opts.inherit_labels {
+            let Some(value) = pod.labels().get(key) else {
+                tracing::debug!("Runner pod doesn't have label {} - Not inheriting", key);
+                continue;
+            };
+
+            if opts.inherit_labels_override || !labels.contains_key(key) {
+                labels.insert(key.clone(), value.clone());
+            }
+        }
+    }
+
+    merge_metadata(
+        &mut vmi.metadata.labels,
+        &opts.vmi_labels,
+        opts.vmi_metadata_override,
+        &env,
+    )
+    .context("Failed to add extra labels")?;
+    merge_metadata(
+        &mut vmi.metadata.annotations,
+        &opts.vmi_annotations,
+        opts.vmi_metadata_override,
+        &env,
+    )
+    .context("Failed to add extra annotations")?;
+
+    // The Secrets are referenced by the VMI, so we need to know their names in advance
+    let secret_prefix = if generate_name {
+        format!("{}-{}", vmi_name, random_suffix())
+    } else {
+        vmi_name.to_string()
+    };
+    let runner_info_secret = format!("{}-{}", secret_prefix, RUNNER_INFO_SECRET_KEY);
+    let ssh_keys_secret = format!("{}-{}", secret_prefix, SSH_KEYS_SECRET_SUFFIX);
+    if opts.annotate_github_context {
+        let annotations = github_context_annotations(&env);
+        if annotations.is_empty() {
+            tracing::warn!("No GitHub workflow context found in the environment");
+        }
+        vmi.metadata
+            .annotations
+            .get_or_insert_with(Default::default)
+            .extend(annotations);
+    }
+
+    let runner_info_json =
+        serde_json::to_string(&runner_info).context("Failed to serialize runner info")?;
+    tracing::debug!("Runner info is {} bytes", runner_info_json.len());
+    if opts.runner_info_mode == RunnerInfoMode::DownwardApi {
+        let size = annotations_size(vmi.metadata.annotations.as_ref())
+            + opts.runner_info_annotation.len()
+            + runner_info_json.len();
+        if size > ANNOTATIONS_SIZE_LIMIT {
+            // The API server would reject the VMI with a less helpful error
+            tracing::warn!(
+                "VMI annotations would be {} bytes with the runner info, over the limit of {} - Switching to --runner-info-mode secret",
+                size,
+                ANNOTATIONS_SIZE_LIMIT
+            );
+            opts.runner_info_mode = RunnerInfoMode::Secret;
+        }
+    }
+    if cloud_init.is_some() && opts.runner_info_mode == RunnerInfoMode::DownwardApi {
+        tracing::warn!(
+            "The cloud-init user data is stored in plaintext in the VMI spec - Pass --runner-info-mode secret to keep the runner token out of it"
+        );
+    }
+
+    let mut data = BTreeMap::new();
+    match opts.runner_info_mode {
+        RunnerInfoMode::DownwardApi => {
+            vmi.metadata
+                .annotations
+                .get_or_insert_with(Default::default)
+                .insert(
+                    opts.runner_info_annotation.clone(),
+                    runner_info_json.clone(),
+                );
+
+            data.insert("downwardAPI".to_string(), serde_json::json!({
+                "fields": [
+                    {
+                        "path": opts.runner_info_path,
+                        "fieldRef": {
+                            "fieldPath": format!("metadata.annotations['{}']", opts.runner_info_annotation)
+                        }
+                    }
+                ]
+            }));
+        }
+        RunnerInfoMode::Secret => {
+            data.insert(
+                "secret".to_string(),
+                serde_json::json!({
+                    "secretName": runner_info_secret,
+                    "items": [
+                        {
+                            "key": RUNNER_INFO_SECRET_KEY,
+                            "path": opts.runner_info_path,
+                        }
+                    ]
+                }),
+            );
+        }
+    }
+
+    // Lets us recognize our VMIs even without the annotation or owner pod label
+    vmi.metadata
+        .labels
+        .get_or_insert_with(Default::default)
+        .insert(MANAGED_BY_LABEL.to_string(), MANAGED_BY.to_string());
+
+    // Used to find orphaned VMIs, which doesn't need the UID
+    if let Some(pod_name) = &opts.pod_name {
+        vmi.metadata
+            .labels
+            .get_or_insert_with(Default::default)
+            .insert(OWNER_POD_LABEL.to_string(), pod_name.clone());
+    }
+
+    match (&opts.pod_name, &opts.pod_uid) {
+        (Some(pod_name), Some(pod_uid)) => {
+            tracing::info!("VMI will be owned by pod {}", pod_name);
+            vmi.metadata
+                .owner_references
+                .get_or_insert_with(Default::default)
+                .push(OwnerReference {
+                    api_version: "v1".to_string(),
+                    kind: "Pod".to_string(),
+                    name: pod_name.clone(),
+                    uid: pod_uid.clone(),
+                    block_owner_deletion: Some(false),
+                    controller: None,
+                });
+        }
+        (None, Some(_)) => {
+            tracing::warn!("POD_UID is set but POD_NAME isn't - Not setting owner reference");
+        }
+        _ => {
+            tracing::debug!("POD_UID is not set - Not setting owner reference");
+        }
+    }
+
+    let volumes = vmi.spec.volumes.get_or_insert_with(Default::default);
+    if let Some(volume) = volumes
+        .iter_mut()
+        .find(|v| v.name == opts.runner_info_volume_name)
+    {
+        volume.data = data;
+    } else {
+        volumes.push({
+            Volume {
+                name: opts.runner_info_volume_name.clone(),
+                data,
+            }
+        });
+    }
+
+    if let Some(CloudInit {
+        volume: volume_name,
+        source: source_kind,
+        user_data,
+    }) = &cloud_init
+    {
+        let source = if opts.runner_info_mode == RunnerInfoMode::Secret {
+            serde_json::json!({
+                "secretRef": {
+                    "name": runner_info_secret,
+                },
+            })
+        } else {
+            serde_json::json!({
+                "userData": user_data,
+            })
+        };
+
+        let volume = match volumes.iter_mut().find(|v| &v.name == volume_name) {
+            Some(volume) => volume,
+            None => {
+                volumes.push(Volume {
+                    name: volume_name.clone(),
+                    data: BTreeMap::new(),
+                });
+                volumes.last_mut().unwrap()
+            }
+        };
+
+        // Keep other settings like networkData, but replace the user data
+        let cloud_init = volume
+            .data
+            .entry(source_kind.to_string())
+            .or_insert_with(|| serde_json::json!({}));
+        let Some(cloud_init) = cloud_init.as_object_mut() else {
+            return Err(RunnerError::Template(anyhow!(
+                "Volume {} has an invalid {} source",
+                volume_name,
+                source_kind
+            )));
+        };
+        for key in ["userData", "userDataBase64", "secretRef"] {
+            cloud_init.remove(key);
+        }
+        if let Value::Object(source) = source {
+            cloud_init.extend(source);
+        }
+    }
+
+    // Created along with the VMI, which waits for them
+    let mut vmi_secrets = Vec::new();
+    if opts.runner_info_mode == RunnerInfoMode::Secret {
+        let mut secret_data = BTreeMap::new();
+        secret_data.insert(RUNNER_INFO_SECRET_KEY.to_string(), runner_info_json);
+        if let Some(cloud_init) = &cloud_init {
+            secret_data.insert(
+                CLOUD_INIT_SECRET_KEY.to_string(),
+                cloud_init.user_data.clone(),
+            );
+        }
+
+        vmi_secrets.push(Secret {
+            metadata: ObjectMeta {
+                name: Some(runner_info_secret.clone()),
+                namespace: Some(apis.namespace.clone()),
+                ..Default::default()
+            },
+            string_data: Some(secret_data),
+            ..Default::default()
+        });
+    }
+
+    let ssh_keys: Vec<&str> = opts
+        .ssh_authorized_keys
+        .iter()
+        .map(|key| key.trim())
+        .filter(|key| !key.is_empty())
+        .collect();
+    if !ssh_keys.is_empty() {
+        add_ssh_access_credentials(&mut vmi.spec, &ssh_keys_secret, &opts.ssh_users)
+            .context("Failed to add SSH access credentials")?;
+
+        let secret_data = ssh_keys
+            .iter()
+            .enumerate()
+            .map(|(i, key)| (format!("key{}", i), key.to_string()))
+            .collect();
+        vmi_secrets.push(Secret {
+            metadata: ObjectMeta {
+                name: Some(ssh_keys_secret.clone()),
+                namespace: Some(apis.namespace.clone()),
+                ..Default::default()
+            },
+            string_data: Some(secret_data),
+            ..Default::default()
+        });
+    }
+
+    let runner_info_secret =
+        (opts.runner_info_mode == RunnerInfoMode::Secret).then_some(runner_info_secret);
+    Ok(Plan {
+        vmi,
+        secrets: vmi_secrets,
+        run_strategy,
+        runner_info_secret,
+    })
+}
+
+/// Fetches the VirtualMachine template by name or label selector.
+pub(crate) async fn fetch_template(
+    api: &Api<VirtualMachine>,
+    namespace: &str,
+    name: Option<&str>,
+    selector: Option<&str>,
+    wait: Duration,
+    stop: &StopHandle,
+) -> AnyResult<VirtualMachine> {
+    let deadline = Instant::now() + wait;
+    let mut attempt = 0;
+    let mut templates = loop {
+        let templates = find_templates(api, namespace, name, selector).await?;
+        if !templates.is_empty() || Instant::now() >= deadline {
+            break templates;
+        }
+
+        attempt += 1;
+        tracing::info!(
+            "VM template not found in {} - Retrying in {:?} (attempt {})",
+            namespace,
+            TEMPLATE_RETRY_INTERVAL,
+            attempt
+        );
+
+        // Don't hold up the teardown of the runner pod
+        tokio::select! {
+            _ = tokio::time::sleep(TEMPLATE_RETRY_INTERVAL) => {}
+            _ = stop.requested() => {
+                return Err(anyhow!("Stopped while waiting for the VM template"));
+            }
+        }
+    };
+
+    match (templates.len(), name) {
+        (1, _) => {}
+        (0, Some(name)) => {
+            return Err(anyhow!(
+                "VirtualMachine {} not found in {}",
+                name,
+                namespace
+            ));
+        }
+        (n, _) => {
+            return Err(anyhow!(
+                "Expected exactly one VirtualMachine matching {} in {}, found {}",
+                selector.unwrap_or_default(),
+                namespace,
+                n
+            ));
+        }
+    }
+
+    let template = templates.remove(0);
+    tracing::info!("Using template {}", template.name_any());
+
+    Ok(template)
+}
+
+/// Returns the VM templates with the name or matching the selector.
+async fn find_templates(
+    api: &Api<VirtualMachine>,
+    namespace: &str,
+    name: Option<&str>,
+    selector: Option<&str>,
+) -> AnyResult<Vec<VirtualMachine>> {
+    let result = match (name, selector) {
+        (Some(name), _) => api
+            .get_opt(name)
+            .await
+            .map(|template| template.into_iter().collect()),
+        (None, Some(selector)) => api
+            .list(&ListParams::default().labels(selector))
+            .await
+            .map(|list| list.items),
+        (None, None) => return Err(anyhow!("No VM template specified")),
+    };
+
+    match result {
+        Ok(templates) => Ok(templates),
+        Err(kube::Error::Api(e)) if e.code == 403 => {
+            Err(kube::Error::Api(e)).with_context(|| {
+                format!(
+                    "Not allowed to fetch VM templates in {} - The service account needs `get` and `list` on `virtualmachines` in the template namespace",
+                    namespace,
+                )
+            })
+        }
+        Err(e) => Err(e).context("Failed to fetch VM template"),
+    }
+}
+
+/// Merges extra labels or annotations into the VMI metadata.
+///
+/// Environment variables in the values are substituted, and values
+/// like `$ENV:NAME` are replaced with the environment variable.
+pub(crate) fn merge_metadata(
+    map: &mut Option<BTreeMap<String, String>>,
+    extra: &[(String, String)],
+    override_: bool,
+    env: &BTreeMap<String, String>,
+) -> AnyResult<()> {
+    if extra.is_empty() {
+        return Ok(());
+    }
+
+    let map = map.get_or_insert_with(Default::default);
+    for (key, value) in extra {
+        if !override_ && map.contains_key(key) {
+            tracing::debug!("{} is already set by the template - Not overriding", key);
+            continue;
+        }
+
+        let value = match value.strip_prefix("$ENV:") {
+            Some(name) => env
+                .get(name)
+                .cloned()
+                .ok_or_else(|| anyhow!("Environment variable {} is not set", name))?,
+            None => substitute_vars(value, &|name| env.get(name).cloned())?,
+        };
+        map.insert(key.clone(), value);
+    }
+
+    Ok(())
+}
+
+/// Returns the size of annotations as counted by Kubernetes.
+pub(crate) fn annotations_size(annotations: Option<&BTreeMap<String, String>>) -> usize {
+    annotations
+        .into_iter()
+        .flatten()
+        .map(|(key, value)| key.len() + value.len())
+        .sum()
+}
+
+/// Returns the annotations describing the GitHub workflow context.
+pub(crate) fn github_context_annotations(
+    env: &BTreeMap<String, String>,
+) -> BTreeMap<String, String> {
+    GITHUB_CONTEXT_VARS
+        .iter()
+        .filter_map(|(var, name)| {
+            let value = non_empty(env.get(*var).map(String::as_str))?;
+            Some((
+                format!("{}{}", GITHUB_CONTEXT_ANNOTATION_PREFIX, name),
+                value.to_string(),
+            ))
+        })
+        .collect()
+}
+
+/// Returns whether the domain has a filesystem or disk using the volume.
+pub(crate) fn has_device_for_volume(spec: &VirtualMachineInstanceSpec, volume: &str) -> bool {
+    let Some(devices) = spec.data.get("domain").and_then(|d| d.get("devices")) else {
+        return false;
+    };
+
+    ["filesystems", "disks"].iter().any(|kind| {
+        devices
+            .get(kind)
+            .and_then(Value::as_array)
+            .is_some_and(|devices| {
+                devices
+                    .iter()
+                    .any(|d| d.get("name").and_then(Value::as_str) == Some(volume))
+            })
+    })
+}
+
+/// Substitutes variables in the VM template.
+pub(crate) fn render_template(
+    template: VirtualMachineTemplate,
+    vars: &BTreeMap<String, String>,
+    env: Option<&BTreeMap<String, String>>,
+) -> AnyResult<VirtualMachineTemplate> {
+    let lookup = |name: &str| {
+        vars.get(name)
+            .or_else(|| env.and_then(|env| env.get(name)))
+            .cloned()
+    };
+
+    let mut value = serde_json::to_value(template)?;
+    substitute_value(&mut value, &lookup).context("Failed to render the VM template")?;
+
+    serde_json::from_value(value).context("Failed to parse the rendered VM template")
+}
+
+/// Substitutes variables in all strings of a JSON value.
+fn substitute_value(value: &mut Value, lookup: &dyn Fn(&str) -> Option<String>) -> AnyResult<()> {
+    match value {
+        Value::String(s) => *s = substitute_vars(s, lookup)?,
+        Value::Array(items) => {
+            for item in items {
+                substitute_value(item, lookup)?;
+            }
+        }
+        Value::Object(map) => {
+            for item in map.values_mut() {
+                substitute_value(item, lookup)?;
+            }
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
+
+/// Overrides the CPU cores, CPU model and memory of the VMI.
+///
+/// Other fields of the domain are left untouched.
+pub(crate) fn override_resources(
+    spec: &mut VirtualMachineInstanceSpec,
+    cpu: Option<u32>,
+    cpu_model: Option<&str>,
+    memory: Option<&str>,
+) -> AnyResult<()> {
+    if cpu.is_none() && cpu_model.is_none() && memory.is_none() {
+        return Ok(());
+    }
+
+    let domain = spec
+        .data
+        .entry("domain".to_string())
+        .or_insert_with(|| Value::Object(Default::default()));
+
+    if let Some(cpu) = cpu {
+        object_at(domain, &["cpu"])?.insert("cores".to_string(), cpu.into());
+    }
+
+    if let Some(cpu_model) = cpu_model {
+        object_at(domain, &["cpu"])?.insert("model".to_string(), cpu_model.into());
+    }
+
+    if let Some(memory) = memory {
+        object_at(domain, &["resources", "requests"])?.insert("memory".to_string(), memory.into());
+
+        // Otherwise the limit may end up lower than the request
+        for pointer in ["/resources/limits/memory", "/memory/guest"] {
+            if let Some(value) = domain.pointer_mut(pointer) {
+                *value = memory.into();
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Adds Multus networks and bridge interfaces for them.
+///
+/// Existing interfaces and networks (e.g., the pod network) are kept.
+pub(crate) fn add_network_attachments(
+    spec: &mut VirtualMachineInstanceSpec,
+    attachments: &[String],
+) -> AnyResult<()> {
+    for attachment in attachments {
+        // Interface names can't contain slashes
+        let name = attachment.replace('/', "-");
+
+        let domain = spec
+            .data
+            .entry("domain".to_string())
+            .or_insert_with(|| Value::Object(Default::default()));
+        let interfaces = array_at(object_at(domain, &["devices"])?, "interfaces")?;
+        if interfaces.iter().any(|i| i["name"] == name.as_str()) {
+            return Err(anyhow!(
+                "The VM template already has an interface named {}",
+                name
+            ));
+        }
+        interfaces.push(serde_json::json!({
+            "name": name,
+            "bridge": {},
+        }));
+
+        let networks = spec
+            .data
+            .entry("networks".to_string())
+            .or_insert_with(|| Value::Array(Vec::new()));
+        let Some(networks) = networks.as_array_mut() else {
+            return Err(anyhow!("Invalid networks in the VM template"));
+        };
+        networks.push(serde_json::json!({
+            "name": name,
+            "multus": {
+                "networkName": attachment,
+            },
+        }));
+    }
+
+    Ok(())
+}
+
+/// Adds Secrets and ConfigMaps as volumes with virtiofs filesystems.
+///
+/// Volume names in `reserved` or already in the spec are rejected.
+pub(crate) fn add_mounts(
+    spec: &mut VirtualMachineInstanceSpec,
+    mounts: &[Mount],
+    reserved: &[&str],
+) -> AnyResult<()> {
+    for mount in mounts {
+        let name = mount.volume_name();
+        let volumes = spec.volumes.get_or_insert_with(Default::default);
+        if reserved.contains(&name.as_str()) || volumes.iter().any(|v| v.name == name) {
+            return Err(anyhow!(
+                "Cannot mount {} - The VMI already has a volume named {}",
+                mount.name,
+                name
+            ));
+        }
+
+        let (source, data) = match mount.kind {
+            MountKind::Secret => ("secret", serde_json::json!({ "secretName": mount.name })),
+            MountKind::ConfigMap => ("configMap", serde_json::json!({ "name": mount.name })),
+        };
+        volumes.push(Volume {
+            name: name.clone(),
+            data: BTreeMap::from([(source.to_string(), data)]),
+        });
+
+        let domain = spec
+            .data
+            .entry("domain".to_string())
+            .or_insert_with(|| Value::Object(Default::default()));
+        array_at(object_at(domain, &["devices"])?, "filesystems")?.push(serde_json::json!({
+            "name": name,
+            "virtiofs": {},
+        }));
+    }
+
+    Ok(())
+}
+
+/// Adds the SSH keys in a Secret to the access credentials of the VMI.
+///
+/// The keys are propagated to `users` by the guest agent.
+pub(crate) fn add_ssh_access_credentials(
+    spec: &mut VirtualMachineInstanceSpec,
+    secret_name: &str,
+    users: &[String],
+) -> AnyResult<()> {
+    let credentials = spec
+        .data
+        .entry("accessCredentials".to_string())
+        .or_insert_with(|| Value::Array(Vec::new()));
+    let Some(credentials) = credentials.as_array_mut() else {
+        return Err(anyhow!("Invalid accessCredentials in the VM template"));
+    };
+
+    credentials.push(serde_json::json!({
+        "sshPublicKey": {
+            "source": {
+                "secret": {
+                    "secretName": secret_name,
+                },
+            },
+            "propagationMethod": {
+                "qemuGuestAgent": {
+                    "users": users,
+                },
+            },
+        },
+    }));
+
+    Ok(())
+}
+
+/// Returns the array under a key, creating it if it doesn't exist.
+fn array_at<'a>(
+    object: &'a mut serde_json::Map<String, Value>,
+    key: &str,
+) -> AnyResult<&'a mut Vec<Value>> {
+    object
+        .entry(key)
+        .or_insert_with(|| Value::Array(Vec::new()))
+        .as_array_mut()
+        .ok_or_else(|| anyhow!("Expected {} to be an array", key))
+}
+
+/// Returns the object at a path, creating it if it doesn't exist.
+fn object_at<'a>(
+    value: &'a mut Value,
+    path: &[&str],
+) -> AnyResult<&'a mut serde_json::Map<String, Value>> {
+    let mut value = value;
+    for key in path {
+        value = value
+            .as_object_mut()
+            .ok_or_else(|| anyhow!("Expected an object before {}", key))?
+            .entry(key.to_string())
+            .or_insert_with(|| Value::Object(Default::default()));
+    }
+
+    value
+        .as_object_mut()
+        .ok_or_else(|| anyhow!("Expected {} to be an object", path.join(".")))
+}
+
+/// Prepares merging cloud-init user data into the VMI spec.
+///
+/// If the spec has a cloud-init volume, the user data is merged with
+/// its user data. Otherwise, a `cloudInitNoCloud` volume named `volume`
+/// will be added, and a disk for it is added here.
+pub(crate) fn merge_cloud_init(
+    spec: &mut VirtualMachineInstanceSpec,
+    volume: &str,
+    user_data: &str,
+) -> AnyResult<CloudInit> {
+    let mut existing = spec.volumes.iter().flatten().filter_map(|v| {
+        ["cloudInitNoCloud", "cloudInitConfigDrive"]
+            .into_iter()
+            .find(|source| v.data.contains_key(*source))
+            .map(|source| (v, source))
+    });
+
+    let Some((existing_volume, source)) = existing.next() else {
+        let domain = spec
+            .data
+            .entry("domain".to_string())
+            .or_insert_with(|| Value::Object(Default::default()));
+        let disks = object_at(domain, &["devices"])?
+            .entry("disks")
+            .or_insert_with(|| Value::Array(Vec::new()));
+        let Some(disks) = disks.as_array_mut() else {
+            return Err(anyhow!("Invalid disks in the VM template"));
+        };
+        disks.push(serde_json::json!({
+            "name": volume,
+            "disk": {
+                "bus": "virtio",
+            },
+        }));
+
+        return Ok(CloudInit {
+            volume: volume.to_string(),
+            source: "cloudInitNoCloud",
+            user_data: user_data.to_string(),
+        });
+    };
+
+    if let Some((other, _)) = existing.next() {
+        return Err(anyhow!(
+            "The VM template has multiple cloud-init volumes ({} and {}) - Cannot decide which to merge into",
+            existing_volume.name,
+            other.name
+        ));
+    }
+
+    let data = &existing_volume.data[source];
+    if data.get("userDataBase64").is_some() || data.get("secretRef").is_some() {
+        return Err(anyhow!(
+            "Cannot merge into the user data of volume {} - Only inline userData is supported",
+            existing_volume.name
+        ));
+    }
+
+    let base = data.get("userData").and_then(Value::as_str).unwrap_or("");
+    Ok(CloudInit {
+        volume: existing_volume.name.clone(),
+        source,
+        user_data: merge_user_data(base, user_data)?,
+    })
+}
+
+/// Merges two `#cloud-config` user data documents.
+///
+/// Top-level lists (like `runcmd` and `write_files`) are concatenated,
+/// and other keys in `extra` replace those in `base`.
+fn merge_user_data(base: &str, extra: &str) -> AnyResult<String> {
+    let mut merged = parse_user_data(base).context("Invalid user data in the VM template")?;
+    for (key, value) in parse_user_data(extra)? {
+        match (merged.get_mut(&key), value) {
+            (Some(serde_yaml::Value::Sequence(base)), serde_yaml::Value::Sequence(extra)) => {
+                base.extend(extra);
+            }
+            (_, value) => {
+                merged.insert(key, value);
+            }
+        }
+    }
+
+    Ok(format!(
+        "{}\n{}",
+        CLOUD_CONFIG_HEADER,
+        serde_yaml::to_string(&merged)?
+    ))
+}
+
+/// Parses `#cloud-config` user data.
+fn parse_user_data(user_data: &str) -> AnyResult<serde_yaml::Mapping> {
+    if user_data.trim().is_empty() {
+        return Ok(Default::default());
+    }
+
+    if !user_data.starts_with(CLOUD_CONFIG_HEADER) {
+        return Err(anyhow!("Only #cloud-config user data can be merged"));
+    }
+
+    let value: serde_yaml::Value = serde_yaml::from_str(user_data)?;
+    match value {
+        serde_yaml::Value::Null => Ok(Default::default()),
+        serde_yaml::Value::Mapping(mapping) => Ok(mapping),
+        _ => Err(anyhow!("User data must be a mapping")),
+    }
+}
+
+/// Renders cloud-init user data with runner info.
+///
+/// With `#cloud-config`, placeholders are replaced in the parsed
+/// strings, so values like tokens or labels can't change the structure
+/// of the document. Other user data is rendered as text.
+pub(crate) fn render_user_data(user_data: &str, runner_info: &RunnerInfo) -> AnyResult<String> {
+    fn render(value: &mut serde_yaml::Value, runner_info: &RunnerInfo) -> AnyResult<()> {
+        match value {
+            serde_yaml::Value::String(s) => *s = render_cloud_init(s, runner_info)?,
+            serde_yaml::Value::Sequence(values) => {
+                for value in values {
+                    render(value, runner_info)?;
+                }
+            }
+            serde_yaml::Value::Mapping(mapping) => {
+                for value in mapping.values_mut() {
+                    render(value, runner_info)?;
+                }
+            }
+            serde_yaml::Value::Tagged(tagged) => render(&mut tagged.value, runner_info)?,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    if !user_data.starts_with(CLOUD_CONFIG_HEADER) {
+        return render_cloud_init(user_data, runner_info);
+    }
+
+    let mut value = serde_yaml::Value::Mapping(parse_user_data(user_data)?);
+    render(&mut value, runner_info)?;
+    Ok(format!(
+        "{}\n{}",
+        CLOUD_CONFIG_HEADER,
+        serde_yaml::to_string(&value)?
+    ))
+}
+
+/// Renders a cloud-init template with runner info.
+///
+/// Placeholders that don't apply to the kind of runner info
+/// (e.g., `{{token}}` for JIT runners) are replaced with an
+/// empty string.
+pub(crate) fn render_cloud_init(template: &str, runner_info: &RunnerInfo) -> AnyResult<String> {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        out.push_str(&rest[..start]);
+        rest = &rest[start + 2..];
+
+        let end = rest
+            .find("}}")
+            .ok_or_else(|| anyhow!("Unterminated placeholder"))?;
+        let name = rest[..end].trim();
+        let proxy = match runner_info {
+            RunnerInfo::Jit(info) => &info.proxy,
+            RunnerInfo::Legacy(info) => &info.proxy,
+        };
+        let value = match (name, runner_info) {
+            ("http_proxy", _) => proxy.http_proxy.clone().unwrap_or_default(),
+            ("https_proxy", _) => proxy.https_proxy.clone().unwrap_or_default(),
+            ("no_proxy", _) => proxy.no_proxy.clone().unwrap_or_default(),
+            ("jitconfig", RunnerInfo::Jit(info)) => info.jitconfig.clone(),
+            ("name", RunnerInfo::Legacy(info)) => info.name.clone(),
+            ("token", RunnerInfo::Legacy(info)) => info.token.clone(),
+            ("url", RunnerInfo::Legacy(info)) => info.url.clone(),
+            ("ephemeral", RunnerInfo::Legacy(info)) => info.ephemeral.to_string(),
+            ("groups", RunnerInfo::Legacy(info)) => info.groups.clone(),
+            ("labels", RunnerInfo::Legacy(info)) => info.labels.clone(),
+            ("jitconfig" | "name" | "token" | "url" | "ephemeral" | "groups" | "labels", _) => {
+                String::new()
+            }
+            _ => return Err(anyhow!("Unknown placeholder {{{{{}}}}}", name)),
+        };
+
+        out.push_str(&value);
+        rest = &rest[end + 2..];
+    }
+    out.push_str(rest);
+
+    Ok(out)
+}
+
+/// Substitutes `${VAR}` references in a string.
+fn substitute_vars(s: &str, lookup: &dyn Fn(&str) -> Option<String>) -> AnyResult<String> {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find('$') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        if let Some(r) = rest.strip_prefix("$${") {
+            out.push_str("${");
+            rest = r;
+        } else if let Some(r) = rest.strip_prefix("${") {
+            let end = r
+                .find('}')
+                .ok_or_else(|| anyhow!("Unterminated variable reference: {}", s))?;
+            let name = &r[..end];
+            let value = lookup(name).ok_or_else(|| anyhow!("Undefined variable {}", name))?;
+
+            out.push_str(&value);
+            rest = &r[end + 1..];
+        } else {
+            out.push('$');
+            rest = &rest[1..];
+        }
+    }
+    out.push_str(rest);
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        opts::parse_mount,
+        runner_info::{JitRunnerInfo, LegacyRunnerInfo, ProxyInfo},
+    };
+
+    #[test]
+    fn test_merge_user_data() {
+        let base = "#cloud-config\nhostname: vm\nruncmd:\n  - echo base\n";
+        let extra = "#cloud-config\nruncmd:\n  - echo extra\nwrite_files:\n  - path: /etc/runner\n";
+        assert_eq!(
+            "#cloud-config\nhostname: vm\nruncmd:\n- echo base\n- echo extra\nwrite_files:\n- path: /etc/runner\n",
+            merge_user_data(base, extra).unwrap()
+        );
+
+        assert_eq!(
+            "#cloud-config\nhostname: vm\n",
+            merge_user_data("", "#cloud-config\nhostname: vm").unwrap()
+        );
+
+        assert!(merge_user_data("#!/bin/sh\necho hi\n", "#cloud-config\n").is_err());
+    }
+
+    #[test]
+    fn test_override_resources() {
+        let mut spec = VirtualMachineInstanceSpec {
+            volumes: None,
+            data: BTreeMap::from([(
+                "domain".to_string(),
+                serde_json::json!({
+                    "cpu": { "cores": 2, "model": "host-passthrough" },
+                    "resources": {
+                        "requests": { "memory": "2Gi" },
+                        "limits": { "memory": "2Gi" },
+                    },
+                    "devices": {},
+                }),
+            )]),
+        };
+
+        override_resources(&mut spec, Some(4), None, Some("8Gi")).unwrap();
+        assert_eq!(
+            serde_json::json!({
+                "cpu": { "cores": 4, "model": "host-passthrough" },
+                "resources": {
+                    "requests": { "memory": "8Gi" },
+                    "limits": { "memory": "8Gi" },
+                },
+                "devices": {},
+            }),
+            spec.data["domain"]
+        );
+
+        let mut spec = VirtualMachineInstanceSpec {
+            volumes: None,
+            data: BTreeMap::new(),
+        };
+        override_resources(&mut spec, None, Some("host-model"), Some("1Gi")).unwrap();
+        assert_eq!(
+            serde_json::json!({
+                "cpu": { "model": "host-model" },
+                "resources": { "requests": { "memory": "1Gi" } },
+            }),
+            spec.data["domain"]
+        );
+    }
+
+    #[test]
+    fn test_add_network_attachments() {
+        let mut spec = VirtualMachineInstanceSpec {
+            volumes: None,
+            data: BTreeMap::from([
+                (
+                    "domain".to_string(),
+                    serde_json::json!({
+                        "devices": {
+                            "interfaces": [{ "name": "default", "masquerade": {} }],
+                        },
+                    }),
+                ),
+                (
+                    "networks".to_string(),
+                    serde_json::json!([{ "name": "default", "pod": {} }]),
+                ),
+            ]),
+        };
+
+        add_network_attachments(&mut spec, &["ci/build-net".to_string()]).unwrap();
+        assert_eq!(
+            serde_json::json!([
+                { "name": "default", "masquerade": {} },
+                { "name": "ci-build-net", "bridge": {} },
+            ]),
+            spec.data["domain"]["devices"]["interfaces"]
+        );
+        assert_eq!(
+            serde_json::json!([
+                { "name": "default", "pod": {} },
+                { "name": "ci-build-net", "multus": { "networkName": "ci/build-net" } },
+            ]),
+            spec.data["networks"]
+        );
+
+        assert!(add_network_attachments(&mut spec, &["ci/build-net".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_add_mounts() {
+        let mut spec = VirtualMachineInstanceSpec {
+            volumes: None,
+            data: BTreeMap::new(),
+        };
+
+        let mounts = [
+            parse_mount("secret:registry-auth:/mnt/creds").unwrap(),
+            parse_mount("configmap:ca.bundle:/etc/ssl/ca").unwrap(),
+        ];
+        add_mounts(&mut spec, &mounts, &["runner-info"]).unwrap();
+        assert_eq!(
+            serde_json::json!([
+                { "name": "registry-auth", "secret": { "secretName": "registry-auth" } },
+                { "name": "ca-bundle", "configMap": { "name": "ca.bundle" } },
+            ]),
+            serde_json::to_value(&spec.volumes).unwrap()
+        );
+        assert_eq!(
+            serde_json::json!([
+                { "name": "registry-auth", "virtiofs": {} },
+                { "name": "ca-bundle", "virtiofs": {} },
+            ]),
+            spec.data["domain"]["devices"]["filesystems"]
+        );
+
+        assert!(add_mounts(&mut spec, &mounts[..1], &[]).is_err());
+        let reserved = parse_mount("secret:runner-info:/mnt").unwrap();
+        assert!(add_mounts(&mut spec, &[reserved], &["runner-info"]).is_err());
+
+        for invalid in [
+            "pvc:data:/mnt",
+            "secret::/mnt",
+            "secret:creds",
+            "secret:creds:mnt",
+        ] {
+            assert!(parse_mount(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_has_device_for_volume() {
+        let spec = |domain: Value| VirtualMachineInstanceSpec {
+            volumes: None,
+            data: BTreeMap::from([("domain".to_string(), domain)]),
+        };
+
+        let filesystem = spec(serde_json::json!({
+            "devices": {
+                "filesystems": [{ "name": "runner-info", "virtiofs": {} }],
+            },
+        }));
+        assert!(has_device_for_volume(&filesystem, "runner-info"));
+        assert!(!has_device_for_volume(&filesystem, "other"));
+
+        let disk = spec(serde_json::json!({
+            "devices": {
+                "disks": [{ "name": "rootdisk" }, { "name": "runner-info", "disk": {} }],
+            },
+        }));
+        assert!(has_device_for_volume(&disk, "runner-info"));
+
+        let none = spec(serde_json::json!({ "devices": {} }));
+        assert!(!has_device_for_volume(&none, "runner-info"));
+    }
+
+    #[test]
+    fn test_substitute_vars() {
+        let lookup = |name: &str| (name == "RUN_ID").then(|| "1234".to_string());
+        let substitute = |s| substitute_vars(s, &lookup);
+
+        assert_eq!("run-1234", substitute("run-${RUN_ID}").unwrap());
+        assert_eq!("1234-1234", substitute("${RUN_ID}-${RUN_ID}").unwrap());
+
+        // `$${` escapes a reference, and other dollar signs are kept
+        assert_eq!("${RUN_ID}", substitute("$${RUN_ID}").unwrap());
+        assert_eq!(
+            "$HOME $$HOME $ 5$",
+            substitute("$HOME $$HOME $ 5$").unwrap()
+        );
+
+        let e = substitute("${UNDEFINED}").unwrap_err();
+        assert_eq!("Undefined variable UNDEFINED", e.to_string());
+        assert!(substitute("${RUN_ID").is_err());
+    }
+
+    #[test]
+    fn test_render_template() {
+        let template: VirtualMachineTemplate = serde_json::from_value(serde_json::json!({
+            "metadata": {
+                "labels": { "run": "${RUN_ID}" },
+            },
+            "spec": {
+                "domain": {
+                    "devices": {
+                        "disks": [{ "name": "${DISK}" }],
+                    },
+                },
+                "volumes": [
+                    {
+                        "name": "script",
+                        "cloudInitNoCloud": { "userData": "echo $${HOME}" },
+                    },
+                ],
+            },
+        }))
+        .unwrap();
+
+        let vars = BTreeMap::from([("DISK".to_string(), "root".to_string())]);
+        let env = BTreeMap::from([
+            ("RUN_ID".to_string(), "1234".to_string()),
+            ("DISK".to_string(), "ignored".to_string()),
+        ]);
+        let rendered = render_template(template.clone(), &vars, Some(&env)).unwrap();
+        let rendered = serde_json::to_value(rendered).unwrap();
+        assert_eq!("1234", rendered["metadata"]["labels"]["run"]);
+        assert_eq!(
+            "root",
+            rendered["spec"]["domain"]["devices"]["disks"][0]["name"]
+        );
+        assert_eq!(
+            "echo ${HOME}",
+            rendered["spec"]["volumes"][0]["cloudInitNoCloud"]["userData"]
+        );
+
+        // The environment is only used if passed
+        let e = render_template(template, &vars, None).unwrap_err();
+        assert!(format!("{:#}", e).contains("Undefined variable RUN_ID"));
+    }
+
+    #[test]
+    fn test_render_cloud_init() {
+        let legacy = RunnerInfo::Legacy(LegacyRunnerInfo {
+            name: "runner".to_string(),
+            token: "secret".to_string(),
+            url: "https://github.com/org".to_string(),
+            ephemeral: true,
+            groups: String::new(),
+            labels: "nixos".to_string(),
+            mounts: BTreeMap::new(),
+            env: BTreeMap::new(),
+            proxy: ProxyInfo::default(),
+        });
+        let jit = RunnerInfo::Jit(JitRunnerInfo {
+            jitconfig: "abc".to_string(),
+            inputs: BTreeMap::new(),
+            mounts: BTreeMap::new(),
+            env: BTreeMap::new(),
+            proxy: ProxyInfo {
+                https_proxy: Some("http://proxy:3128".to_string()),
+                ..Default::default()
+            },
+        });
+
+        let template = "url: {{url}}\ntoken: {{ token }}\njit: {{jitconfig}}\n";
+        assert_eq!(
+            "url: https://github.com/org\ntoken: secret\njit: \n",
+            render_cloud_init(template, &legacy).unwrap()
+        );
+        assert_eq!(
+            "url: \ntoken: \njit: abc\n",
+            render_cloud_init(template, &jit).unwrap()
+        );
+
+        assert_eq!(
+            "https_proxy: http://proxy:3128",
+            render_cloud_init("https_proxy: {{https_proxy}}", &jit).unwrap()
+        );
+
+        assert!(render_cloud_init("{{unknown}}", &legacy).is_err());
+        assert!(render_cloud_init("{{url", &legacy).is_err());
+    }
+
+    #[test]
+    fn test_render_user_data() {
+        let info = RunnerInfo::Legacy(LegacyRunnerInfo {
+            name: "runner".to_string(),
+            token: "secret".to_string(),
+            url: "https://github.com/org".to_string(),
+            ephemeral: true,
+            groups: String::new(),
+            labels: "a: b\n#c".to_string(),
+            mounts: BTreeMap::new(),
+            env: BTreeMap::new(),
+            proxy: ProxyInfo::default(),
+        });
+
+        // Values that would break the YAML if substituted into the text
+        let user_data = "#cloud-config\nruncmd:\n  - [echo, '{{labels}}']\n";
+        let rendered = render_user_data(user_data, &info).unwrap();
+        assert!(rendered.starts_with("#cloud-config\n"));
+        let value: serde_yaml::Value = serde_yaml::from_str(&rendered).unwrap();
+        assert_eq!(Some("a: b\n#c"), value["runcmd"][0][1].as_str());
+
+        assert_eq!(
+            "#!/bin/sh\necho runner\n",
+            render_user_data("#!/bin/sh\necho {{name}}\n", &info).unwrap()
+        );
+        assert!(render_user_data("#cloud-config\nruncmd: ['{{unknown}}']\n", &info).is_err());
+    }
+
+    #[test]
+    fn test_add_ssh_access_credentials() {
+        let mut spec: VirtualMachineInstanceSpec = serde_json::from_value(serde_json::json!({
+            "accessCredentials": [
+                {
+                    "userPassword": {},
+                },
+            ],
+        }))
+        .unwrap();
+
+        add_ssh_access_credentials(&mut spec, "runner-ssh-keys", &["root".to_string()]).unwrap();
+
+        let credentials = spec.data["accessCredentials"].as_array().unwrap();
+        assert_eq!(2, credentials.len());
+        assert_eq!(
+            "runner-ssh-keys",
+            credentials[1]["sshPublicKey"]["source"]["secret"]["secretName"]
+        );
+        assert_eq!(
+            serde_json::json!(["root"]),
+            credentials[1]["sshPublicKey"]["propagationMethod"]["qemuGuestAgent"]["users"]
+        );
+    }
+
+    #[test]
+    fn test_annotations_size() {
+        assert_eq!(0, annotations_size(None));
+
+        let annotations = [("a/b".to_string(), "value".to_string())].into();
+        assert_eq!(8, annotations_size(Some(&annotations)));
+    }
+
+    #[test]
+    fn test_github_context_annotations() {
+        let env = [
+            ("GITHUB_REPOSITORY", "owner/repo"),
+            ("GITHUB_RUN_ID", "1234"),
+            ("GITHUB_JOB", ""),
+        ]
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .into();
+
+        let annotations = github_context_annotations(&env);
+        assert_eq!(2, annotations.len());
+        assert_eq!(
+            Some("owner/repo"),
+            annotations
+                .get("li.zhaofeng.kubevirt-actions-runner/github-repository")
+                .map(String::as_str)
+        );
+        assert_eq!(
+            Some("1234"),
+            annotations
+                .get("li.zhaofeng.kubevirt-actions-runner/github-run-id")
+                .map(String::as_str)
+        );
+        assert!(github_context_annotations(&BTreeMap::new()).is_empty());
+    }
+}
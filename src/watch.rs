@@ -0,0 +1,944 @@
+//! Watching the VMI until it's done.
+
+use std::time::{Duration, SystemTime};
+
+use anyhow::Context;
+use futures::StreamExt;
+use k8s_openapi::api::core::v1::Pod;
+use kube::{
+    api::{Api, ListParams},
+    core::ApiResource,
+    runtime::{events::EventType, reflector, wait::await_condition, watcher, WatchStreamExt},
+    ResourceExt,
+};
+use tokio::sync::watch;
+use tokio::time::{timeout_at, Instant};
+use tracing::Instrument;
+
+use crate::{
+    error::RunnerError,
+    events::EventPublisher,
+    github::RunnerRegistry,
+    launcher::{StopHandle, StopRequest},
+    lifecycle::{Apis, Created},
+    metrics::Metrics,
+    opts::{Opts, StartMode},
+    runner_info::non_empty,
+    FailureReason, VirtualMachineInstance, VirtualMachineInstanceCondition,
+    VirtualMachineInstanceStatus, VmiOutcome, IMAGE_PULL_POLL_INTERVAL, REGISTRATION_POLL_INTERVAL,
+    WATCH_HEARTBEAT_INTERVAL,
+};
+
+/// VMI and condition reasons that indicate a VMI failed because of the infrastructure.
+const INFRASTRUCTURE_FAILURE_REASONS: &[&str] = &[
+    "Evicted",
+    "NodeUnresponsive",
+    "PodNotExists",
+    "PodTerminating",
+    "Preempted",
+];
+
+/// What we know about the VMI while watching it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VmiState {
+    /// Whether the VMI is ready to run jobs.
+    pub ready: bool,
+
+    /// The primary IP address of the guest, if reported.
+    pub guest_ip: Option<String>,
+
+    /// The last phase of the VMI.
+    pub phase: Option<String>,
+
+    /// How long it took the VMI to reach `Running`.
+    pub startup: Option<Duration>,
+
+    /// Whether we have given up watching the VMI after too many
+    /// consecutive errors.
+    pub watch_failing: bool,
+
+    /// When we last heard about the VMI from the API server, once we
+    /// have started watching it.
+    pub last_progress: Option<Instant>,
+}
+
+impl VmiState {
+    /// Returns whether we are proceeding normally.
+    ///
+    /// This is false once we have given up watching the VMI, once the
+    /// VMI has failed, or if we haven't heard about the VMI for longer
+    /// than `stall_timeout`. Transient watch errors don't count.
+    pub(crate) fn is_healthy(&self, stall_timeout: Duration) -> bool {
+        !self.watch_failing
+            && self.phase.as_deref() != Some("Failed")
+            && self
+                .last_progress
+                .is_none_or(|last| last.elapsed() <= stall_timeout)
+    }
+}
+
+/// Options for watching the VMI.
+#[derive(Debug, Clone)]
+pub struct WatchOptions {
+    /// How long to wait for the VMI to reach `Running`.
+    pub startup_timeout: Duration,
+
+    /// How long to wait for the guest agent to connect, if at all.
+    pub guest_agent_timeout: Option<Duration>,
+
+    /// How many consecutive watch errors to tolerate.
+    pub max_watch_errors: u32,
+
+    /// The annotation marking the guest as ready, if any.
+    pub ready_annotation: Option<String>,
+
+    /// How long to wait for `ready_annotation` to appear.
+    pub ready_timeout: Duration,
+
+    /// How long after its creation to keep waiting for the VMI to reach `Running` while images are being pulled.
+    pub image_pull_timeout: Option<Duration>,
+
+    /// The pods to look for the virt-launcher pod in when images may be pulled.
+    pub launcher_pods: Option<Api<Pod>>,
+
+    /// When the VMI was created.
+    ///
+    /// The startup time is measured from this, or from when we
+    /// started watching if unknown.
+    pub created: Option<Instant>,
+}
+
+/// Logs why the containers of the virt-launcher pod of a VMI aren't running.
+///
+/// The reasons (e.g., `ImagePullBackOff` or `OOMKilled`) are usually
+/// more helpful than the phase of the VMI.
+pub(crate) async fn log_launcher_diagnostics(pods: &Api<Pod>, vmi_uid: &str) {
+    let lp = ListParams::default().labels(&format!("kubevirt.io/created-by={}", vmi_uid));
+    let pods = match pods.list(&lp).await {
+        Ok(pods) => pods.items,
+        Err(kube::Error::Api(e)) if e.code == 403 => {
+            tracing::warn!("Not allowed to list pods - Cannot inspect the virt-launcher pod");
+            return;
+        }
+        Err(e) => {
+            tracing::warn!("Failed to list virt-launcher pods: {}", e);
+            return;
+        }
+    };
+
+    if pods.is_empty() {
+        tracing::warn!("The VMI has no virt-launcher pod");
+        return;
+    }
+
+    for pod in pods {
+        let pod_name = pod.name_any();
+        let Some(status) = pod.status else {
+            continue;
+        };
+
+        let statuses = status
+            .init_container_statuses
+            .into_iter()
+            .flatten()
+            .chain(status.container_statuses.into_iter().flatten());
+
+        for container in statuses {
+            let Some(state) = container.state else {
+                continue;
+            };
+
+            if let Some(waiting) = state.waiting {
+                tracing::warn!(
+                    "Container {} of virt-launcher pod {} is waiting: {} ({})",
+                    container.name,
+                    pod_name,
+                    waiting.reason.unwrap_or_default(),
+                    waiting.message.unwrap_or_default(),
+                );
+            }
+
+            if let Some(terminated) = state.terminated {
+                if terminated.exit_code != 0 {
+                    tracing::warn!(
+                        "Container {} of virt-launcher pod {} has terminated with exit code {}: {} ({})",
+                        container.name,
+                        pod_name,
+                        terminated.exit_code,
+                        terminated.reason.unwrap_or_default(),
+                        terminated.message.unwrap_or_default(),
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Returns the images that the containers of a virt-launcher pod are waiting for.
+fn pulling_images(pod: &Pod) -> Vec<String> {
+    let Some(status) = &pod.status else {
+        return Vec::new();
+    };
+
+    status
+        .init_container_statuses
+        .iter()
+        .flatten()
+        .chain(status.container_statuses.iter().flatten())
+        .filter(|container| {
+            // The image ID is only set once the image is present
+            container.image_id.is_empty()
+                && container
+                    .state
+                    .as_ref()
+                    .is_some_and(|state| state.waiting.is_some())
+        })
+        .map(|container| container.image.clone())
+        .collect()
+}
+
+/// Returns when a VMI was created according to its `creationTimestamp`.
+pub(crate) fn creation_instant(vmi: &VirtualMachineInstance) -> Option<Instant> {
+    Instant::now().checked_sub(vmi_age(vmi)?)
+}
+
+/// Returns how long ago a VMI was created according to its `creationTimestamp`.
+pub(crate) fn vmi_age(vmi: &VirtualMachineInstance) -> Option<Duration> {
+    let created = vmi.metadata.creation_timestamp.as_ref()?;
+    SystemTime::now().duration_since(created.0.into()).ok()
+}
+
+/// Returns when to stop waiting for images to be pulled for a VMI.
+///
+/// `timeout` is measured from the creation of the VMI, or from
+/// `start` if it isn't known.
+fn image_pull_deadline(
+    vmi: Option<&VirtualMachineInstance>,
+    timeout: Duration,
+    start: Instant,
+) -> Instant {
+    let created = vmi.and_then(|vmi| vmi.metadata.creation_timestamp.as_ref());
+    let Some(created) = created else {
+        return start + timeout;
+    };
+
+    let age = SystemTime::now()
+        .duration_since(created.0.into())
+        .unwrap_or_default();
+    Instant::now() + timeout.saturating_sub(age)
+}
+
+/// Returns the images the virt-launcher pods of a VMI are still pulling.
+async fn launcher_pulling_images(pods: &Api<Pod>, vmi_uid: &str) -> Vec<String> {
+    let lp = ListParams::default().labels(&format!("kubevirt.io/created-by={}", vmi_uid));
+    match pods.list(&lp).await {
+        Ok(pods) => pods.items.iter().flat_map(pulling_images).collect(),
+        Err(e) => {
+            tracing::warn!("Failed to list virt-launcher pods: {}", e);
+            Vec::new()
+        }
+    }
+}
+
+/// Returns whether the VMI is running.
+pub(crate) fn is_vmi_running(vmi: Option<&VirtualMachineInstance>) -> bool {
+    vmi.and_then(|vmi| vmi.status.as_ref())
+        .is_some_and(|status| status.phase == "Running")
+}
+
+/// Returns whether the guest agent of the VMI has connected.
+fn is_agent_connected(conditions: &[VirtualMachineInstanceCondition]) -> bool {
+    conditions
+        .iter()
+        .any(|c| c.type_ == "AgentConnected" && c.status == "True")
+}
+
+/// Determines whether a failed VMI is the fault of the guest or the infrastructure.
+fn classify_failure(status: &VirtualMachineInstanceStatus) -> FailureReason {
+    let is_infrastructure = |reason: Option<&str>| {
+        reason.is_some_and(|reason| INFRASTRUCTURE_FAILURE_REASONS.contains(&reason))
+    };
+    let infrastructure = is_infrastructure(status.reason.as_deref())
+        || status.evacuation_node_name.is_some()
+        || status
+            .conditions
+            .iter()
+            .any(|c| c.status == "False" && is_infrastructure(c.reason.as_deref()));
+
+    if infrastructure {
+        for c in &status.conditions {
+            if let Some(message) = &c.message {
+                tracing::warn!("VMI condition {}: {}", c.type_, message);
+            }
+        }
+
+        FailureReason::Infrastructure
+    } else {
+        FailureReason::Guest
+    }
+}
+
+/// Returns whether the VMI has stopped or is gone.
+pub(crate) fn is_vmi_stopped(vmi: Option<&VirtualMachineInstance>) -> bool {
+    match vmi {
+        Some(vmi) => vmi
+            .status
+            .as_ref()
+            .is_some_and(|status| matches!(status.phase.as_str(), "Succeeded" | "Failed")),
+        None => true,
+    }
+}
+
+/// Returns the first IP address reported for the guest.
+///
+/// Not all network setups report IP addresses, so this is best-effort.
+fn guest_ip(status: &VirtualMachineInstanceStatus) -> Option<&str> {
+    status
+        .interfaces
+        .iter()
+        .find_map(|interface| non_empty(interface.ip_address.as_deref()))
+}
+
+/// Updates whether the VMI is ready, only notifying on changes.
+fn set_ready(state: &watch::Sender<VmiState>, ready: bool) {
+    state.send_if_modified(|state| {
+        let changed = state.ready != ready;
+        state.ready = ready;
+        changed
+    });
+}
+
+/// Records that we have heard about the VMI, without notifying anyone.
+fn mark_progress(state: &watch::Sender<VmiState>) {
+    state.send_if_modified(|state| {
+        state.last_progress = Some(Instant::now());
+        false
+    });
+}
+
+/// Watches the runner VMI until it's done, we are asked to stop, or it
+/// runs for too long.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn watch_runner(
+    apis: &Apis,
+    opts: &Opts,
+    created: &Created,
+    registry: Option<&RunnerRegistry>,
+    events: &EventPublisher,
+    metrics: &Metrics,
+    state: &watch::Sender<VmiState>,
+    stop: &StopHandle,
+) -> Result<VmiOutcome, RunnerError> {
+    let vmi_name = created.vmi.name_any();
+    let vmi_uid = created.vmi.uid().unwrap_or_default();
+
+    let watch_options = WatchOptions {
+        startup_timeout: opts.startup_timeout,
+        guest_agent_timeout: opts.wait_guest_agent.then_some(opts.guest_agent_timeout),
+        max_watch_errors: opts.max_watch_errors,
+        ready_annotation: opts.ready_annotation.clone(),
+        ready_timeout: opts.ready_timeout,
+        image_pull_timeout: opts.image_pull_timeout,
+        launcher_pods: Some(apis.pods.clone()),
+        created: Some(created.created),
+    };
+
+    // JIT runners are always ephemeral
+    let ephemeral = opts.ephemeral || opts.jitconfig.is_some();
+
+    let max_runtime = async {
+        let Some(max_runtime) = opts.max_runtime else {
+            return std::future::pending().await;
+        };
+
+        if let Err(e) = await_condition(apis.vmis.clone(), &vmi_name, is_vmi_running).await {
+            tracing::warn!(
+                "Failed to wait for the VMI to run - Not enforcing max runtime: {}",
+                e
+            );
+            return std::future::pending().await;
+        }
+
+        let running = Instant::now();
+        tokio::time::sleep(max_runtime).await;
+        running.elapsed()
+    };
+
+    let registration = async {
+        let Some(registry) = registry else {
+            return std::future::pending().await;
+        };
+
+        if let Err(e) = await_condition(apis.vmis.clone(), &vmi_name, is_vmi_running).await {
+            tracing::warn!(
+                "Failed to wait for the VMI to run - Not checking registration: {}",
+                e
+            );
+            return std::future::pending().await;
+        }
+
+        tracing::info!("Waiting for runner {} to register", opts.name);
+        let deadline = Instant::now() + opts.registration_timeout;
+        loop {
+            match registry.is_online(&opts.name).await {
+                Ok(true) => {
+                    tracing::info!("Runner {} has registered - Runner online", opts.name);
+                    events
+                        .publish(
+                            EventType::Normal,
+                            "RunnerRegistered",
+                            format!("Runner {} has registered", opts.name),
+                        )
+                        .await;
+                    return std::future::pending().await;
+                }
+                Ok(false) => {}
+                Err(e) => {
+                    tracing::warn!("Failed to check runner registration: {}", e);
+                }
+            }
+
+            if Instant::now() >= deadline {
+                return;
+            }
+            tokio::time::sleep(REGISTRATION_POLL_INTERVAL).await;
+        }
+    };
+
+    tracing::info!("Watching VMI");
+    let outcome = tokio::select! {
+        request = stop.requested() => {
+            tracing::info!("Stop requested ({:?})", request);
+
+            let running = matches!(apis.vmis.get_opt(&vmi_name).await, Ok(vmi) if is_vmi_running(vmi.as_ref()));
+            if request == StopRequest::Terminate && ephemeral && running {
+                tracing::info!("Stopping the ephemeral runner");
+                VmiOutcome::Stopped
+            } else {
+                VmiOutcome::WatchInterrupted
+            }
+        }
+        elapsed = max_runtime => {
+            tracing::warn!("VMI has been running for {:?}, exceeding the max runtime - Shutting it down", elapsed);
+            VmiOutcome::MaxRuntimeExceeded
+        }
+        _ = registration => {
+            tracing::warn!("Runner did not register within {:?}", opts.registration_timeout);
+            VmiOutcome::RegistrationTimedOut
+        }
+        outcome = wait_for_vmi(apis.vmis.clone(), &apis.vmi_resource, &vmi_name, &watch_options, events, metrics, state)
+            .instrument(tracing::info_span!("watch_vmi", otel.kind = "internal", vmi_name = %vmi_name, vmi_uid = %vmi_uid)) => {
+            let outcome = outcome?;
+
+            match outcome {
+                VmiOutcome::Succeeded => {
+                    tracing::info!("VMI has terminated");
+                }
+                VmiOutcome::Failed { reason, .. } => {
+                    tracing::info!("VMI has failed ({:?})", reason);
+                }
+                VmiOutcome::Deleted => {
+                    tracing::info!("VMI was deleted by something");
+                }
+                VmiOutcome::WatchInterrupted => {
+                    tracing::info!("The stream ended prematurely");
+                }
+                // Not returned by `wait_for_vmi`
+                VmiOutcome::Stopped
+                | VmiOutcome::MaxRuntimeExceeded
+                | VmiOutcome::RegistrationTimedOut => {}
+                VmiOutcome::StartupTimedOut { ref last_phase } => {
+                    tracing::info!("VMI did not start in time (last phase: {})", last_phase);
+                }
+            }
+
+            outcome
+        }
+    };
+
+    // Operators may stop the VM instead of deleting the VMI
+    let outcome = match outcome {
+        VmiOutcome::Succeeded | VmiOutcome::Deleted if opts.start_mode == StartMode::CreateVm => {
+            match apis.vms.get_opt(&vmi_name).await {
+                Ok(Some(vm)) if vm.spec.run_strategy.as_deref() == Some("Halted") => {
+                    tracing::info!("VM was stopped by something");
+                    VmiOutcome::Stopped
+                }
+                Ok(_) => outcome,
+                Err(e) => {
+                    tracing::warn!("Failed to get VM: {}", e);
+                    outcome
+                }
+            }
+        }
+        outcome => outcome,
+    };
+
+    Ok(outcome)
+}
+
+/// Waits until the VMI terminates.
+///
+/// If the VMI doesn't reach `Running` within `startup_timeout`,
+/// we give up and return `VmiOutcome::StartupTimedOut`.
+///
+/// If `guest_agent_timeout` is set, we also wait for the guest
+/// agent to connect once the VMI is running, and warn if it
+/// doesn't within the timeout.
+///
+/// Errors from the watcher are retried, and we only give up after
+/// `max_watch_errors` consecutive errors. The VMI is kept in a
+/// reflector store, so we always look at its last known state
+/// even when the watch is restarted.
+///
+/// If `ready_annotation` is set, the VMI is only considered ready
+/// once it also has the annotation, or `ready_timeout` has passed
+/// since it started running.
+///
+/// If `image_pull_timeout` and `launcher_pods` are set, the startup
+/// timeout is extended up to `image_pull_timeout` after the VMI was
+/// created as long as the virt-launcher pod is pulling images.
+///
+/// Whether the VMI is ready and its guest IP are sent to `state`, as
+/// well as when we last heard about the VMI. We check in on the VMI
+/// once in a while even if the watch is quiet.
+pub async fn wait_for_vmi(
+    api: Api<VirtualMachineInstance>,
+    resource: &ApiResource,
+    name: &str,
+    options: &WatchOptions,
+    events: &EventPublisher,
+    metrics: &Metrics,
+    state: &watch::Sender<VmiState>,
+) -> Result<VmiOutcome, RunnerError> {
+    let WatchOptions {
+        startup_timeout,
+        guest_agent_timeout,
+        max_watch_errors,
+        ref ready_annotation,
+        ready_timeout,
+        image_pull_timeout,
+        ref launcher_pods,
+        created,
+    } = *options;
+
+    let writer = reflector::store::Writer::new(resource.clone());
+    let store = writer.as_reader();
+    let mut stream = Box::pin(
+        reflector(
+            writer,
+            watcher::watcher(
+                api.clone(),
+                watcher::Config {
+                    field_selector: Some(format!("metadata.name={}", name)),
+                    ..Default::default()
+                },
+            ),
+        )
+        .default_backoff(),
+    );
+
+    let start = Instant::now();
+    let created = created.unwrap_or(start);
+    let mut startup_deadline = start + startup_timeout;
+    let mut running = false;
+    // The watcher yields the VMI again whenever it reconnects, so we
+    // only log a transition if the phase differs from the last one seen
+    let mut last_phase = "Unknown".to_string();
+    let mut waiting_for_agent = guest_agent_timeout.is_some();
+    let mut agent_deadline = None;
+    let mut waiting_for_annotation = ready_annotation.is_some();
+    let mut annotation_deadline = None;
+    let mut consecutive_errors = 0;
+    mark_progress(state);
+    loop {
+        let deadline = if running {
+            [agent_deadline, annotation_deadline]
+                .into_iter()
+                .flatten()
+                .min()
+        } else {
+            Some(startup_deadline)
+        };
+
+        // Wake up once in a while even when nothing happens
+        let heartbeat = Instant::now() + WATCH_HEARTBEAT_INTERVAL;
+        let wake = deadline.map_or(heartbeat, |deadline| deadline.min(heartbeat));
+        let event = match timeout_at(wake, stream.next()).await {
+            Ok(event) => event,
+            Err(_) if deadline.is_none_or(|deadline| deadline > Instant::now()) => {
+                // A quiet watch is normal while the job is running, so
+                // check that the API server still knows about the VMI
+                match api.get_opt(name).await {
+                    Ok(_) => mark_progress(state),
+                    Err(e) => tracing::warn!("Failed to get VMI: {}", e),
+                }
+                continue;
+            }
+            Err(_) if running => {
+                let now = Instant::now();
+
+                // Keep watching, the VM may still work without the agent
+                if agent_deadline.is_some_and(|deadline| deadline <= now) {
+                    tracing::warn!(
+                        "Guest agent hasn't connected within {:?}",
+                        guest_agent_timeout.unwrap_or_default()
+                    );
+                    events
+                        .publish(
+                            EventType::Warning,
+                            "GuestAgentTimedOut",
+                            format!("Guest agent of VMI {} hasn't connected", name),
+                        )
+                        .await;
+                    agent_deadline = None;
+                }
+
+                if annotation_deadline.is_some_and(|deadline| deadline <= now) {
+                    tracing::warn!(
+                        "VMI hasn't been marked ready within {:?} - Assuming it is",
+                        ready_timeout
+                    );
+                    events
+                        .publish(
+                            EventType::Warning,
+                            "ReadyTimedOut",
+                            format!("VMI {} hasn't been marked ready", name),
+                        )
+                        .await;
+                    waiting_for_annotation = false;
+                    annotation_deadline = None;
+                    set_ready(state, last_phase == "Running");
+                }
+
+                continue;
+            }
+            Err(_) => {
+                let now = Instant::now();
+                let vmi = store.state().into_iter().next();
+                let uid = vmi.as_ref().and_then(|obj| obj.metadata.uid.clone());
+                let pull_deadline = image_pull_timeout
+                    .map(|timeout| image_pull_deadline(vmi.as_deref(), timeout, start));
+                if let (Some(pods), Some(pull_deadline), Some(uid)) =
+                    (launcher_pods, pull_deadline, uid)
+                {
+                    let images = if now < pull_deadline {
+                        launcher_pulling_images(pods, &uid).await
+                    } else {
+                        Vec::new()
+                    };
+                    if !images.is_empty() {
+                        tracing::info!(
+                            "Still pulling image {} ({:?} elapsed)",
+                            images.join(", "),
+                            start.elapsed()
+                        );
+                        startup_deadline = pull_deadline.min(now + IMAGE_PULL_POLL_INTERVAL);
+                        continue;
+                    }
+                }
+
+                return Ok(VmiOutcome::StartupTimedOut { last_phase });
+            }
+        };
+
+        let Some(event) = event else {
+            break;
+        };
+
+        let obj = match event {
+            Ok(_) => {
+                consecutive_errors = 0;
+
+                // The event has already been applied to the store
+                match store.state().into_iter().next() {
+                    Some(obj) => VirtualMachineInstance::clone(&obj),
+                    None => return Ok(VmiOutcome::Deleted),
+                }
+            }
+            Err(e) => {
+                // The watcher recovers by itself, but we may have missed
+                // a transition in the meantime
+                consecutive_errors += 1;
+                if consecutive_errors > max_watch_errors {
+                    state.send_modify(|state| state.watch_failing = true);
+                    return Err(e)
+                        .context("Too many consecutive watch errors")
+                        .context("Failed to watch VMI")
+                        .map_err(RunnerError::Watch);
+                }
+
+                tracing::warn!(
+                    "Failed to watch VMI: {} - Re-checking ({}/{})",
+                    e,
+                    consecutive_errors,
+                    max_watch_errors
+                );
+                match api.get_opt(name).await {
+                    Ok(Some(obj)) => obj,
+                    Ok(None) => return Ok(VmiOutcome::Deleted),
+                    Err(e) => {
+                        tracing::warn!("Failed to get VMI: {}", e);
+                        continue;
+                    }
+                }
+            }
+        };
+        mark_progress(state);
+
+        if let Some(status) = obj.status {
+            tracing::debug!(phase = %status.phase, "VMI has phase: {}", status.phase);
+
+            if let Some(ip) = guest_ip(&status) {
+                if state.borrow().guest_ip.as_deref() != Some(ip) {
+                    tracing::info!(guest_ip = ip, "VMI has guest IP {}", ip);
+                    state.send_modify(|state| state.guest_ip = Some(ip.to_string()));
+                }
+            }
+
+            if status.phase != last_phase {
+                tracing::info!(phase = %status.phase, "VMI has transitioned to {}", status.phase);
+                metrics.set_phase(&status.phase);
+                state.send_modify(|state| state.phase = Some(status.phase.clone()));
+
+                let event_type = if status.phase == "Failed" {
+                    EventType::Warning
+                } else {
+                    EventType::Normal
+                };
+                events
+                    .publish(
+                        event_type,
+                        &format!("VMI{}", status.phase),
+                        format!("VMI {} has transitioned to {}", name, status.phase),
+                    )
+                    .await;
+
+                match status.phase.as_str() {
+                    "Running" => {
+                        running = true;
+                        let startup = created.elapsed();
+                        metrics.observe_startup(startup);
+                        state.send_modify(|state| state.startup = Some(startup));
+
+                        if let Some(guest_agent_timeout) = guest_agent_timeout {
+                            tracing::info!("Waiting for the guest agent to connect");
+                            agent_deadline = Some(Instant::now() + guest_agent_timeout);
+                        }
+
+                        if let Some(ready_annotation) = ready_annotation {
+                            tracing::info!("Waiting for the {} annotation", ready_annotation);
+                            annotation_deadline = Some(Instant::now() + ready_timeout);
+                        }
+                    }
+                    "Succeeded" => {
+                        return Ok(VmiOutcome::Succeeded);
+                    }
+                    "Failed" => {
+                        return Ok(VmiOutcome::Failed {
+                            reason: classify_failure(&status),
+                            booted: running,
+                        });
+                    }
+                    _ => {}
+                }
+                last_phase = status.phase;
+            }
+
+            if running && waiting_for_agent && is_agent_connected(&status.conditions) {
+                waiting_for_agent = false;
+                agent_deadline = None;
+
+                let os = status
+                    .guest_os_info
+                    .and_then(|info| info.pretty_name)
+                    .unwrap_or_else(|| "unknown OS".to_string());
+                tracing::info!("Guest agent has connected ({}) - Runner online", os);
+                events
+                    .publish(
+                        EventType::Normal,
+                        "GuestAgentConnected",
+                        format!("Guest agent of VMI {} has connected ({})", name, os),
+                    )
+                    .await;
+            }
+        } else {
+            tracing::debug!("VMI has no status");
+        }
+
+        if let Some(ready_annotation) = ready_annotation {
+            if running
+                && waiting_for_annotation
+                && obj
+                    .metadata
+                    .annotations
+                    .as_ref()
+                    .is_some_and(|annotations| annotations.contains_key(ready_annotation))
+            {
+                waiting_for_annotation = false;
+                annotation_deadline = None;
+
+                tracing::info!("VMI has been marked ready");
+                events
+                    .publish(
+                        EventType::Normal,
+                        "VMIReady",
+                        format!("VMI {} has been marked ready", name),
+                    )
+                    .await;
+            }
+        }
+
+        set_ready(state, last_phase == "Running" && !waiting_for_annotation);
+    }
+
+    Ok(VmiOutcome::WatchInterrupted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify_failure() {
+        let status = |value: serde_json::Value| -> VirtualMachineInstanceStatus {
+            serde_json::from_value(value).unwrap()
+        };
+
+        let cases = [
+            (
+                FailureReason::Guest,
+                serde_json::json!({ "phase": "Failed" }),
+            ),
+            (
+                FailureReason::Guest,
+                serde_json::json!({ "phase": "Failed", "reason": "SomethingElse" }),
+            ),
+            (
+                FailureReason::Infrastructure,
+                serde_json::json!({ "phase": "Failed", "reason": "NodeUnresponsive" }),
+            ),
+            (
+                FailureReason::Infrastructure,
+                serde_json::json!({ "phase": "Failed", "evacuationNodeName": "node" }),
+            ),
+            (
+                FailureReason::Infrastructure,
+                serde_json::json!({
+                    "phase": "Failed",
+                    "conditions": [{ "type": "Ready", "status": "False", "reason": "PodTerminating" }],
+                }),
+            ),
+            (
+                FailureReason::Guest,
+                serde_json::json!({
+                    "phase": "Failed",
+                    "conditions": [{ "type": "Ready", "status": "True", "reason": "PodTerminating" }],
+                }),
+            ),
+        ];
+
+        for (expected, value) in cases {
+            assert_eq!(
+                expected,
+                classify_failure(&status(value.clone())),
+                "{}",
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn test_image_pull_deadline() {
+        let timeout = Duration::from_secs(30 * 60);
+        let start = Instant::now();
+        assert_eq!(start + timeout, image_pull_deadline(None, timeout, start));
+
+        let created = k8s_openapi::chrono::Utc::now() - k8s_openapi::chrono::Duration::hours(1);
+        let vmi: VirtualMachineInstance = serde_json::from_value(serde_json::json!({
+            "apiVersion": "kubevirt.io/v1",
+            "kind": "VirtualMachineInstance",
+            "metadata": {
+                "name": "runner",
+                "creationTimestamp": created,
+            },
+            "spec": {},
+        }))
+        .unwrap();
+        assert!(image_pull_deadline(Some(&vmi), timeout, start) <= Instant::now());
+    }
+
+    #[test]
+    fn test_vmi_age() {
+        let created = k8s_openapi::chrono::Utc::now() - k8s_openapi::chrono::Duration::hours(2);
+        let mut vmi: VirtualMachineInstance = serde_json::from_value(serde_json::json!({
+            "apiVersion": "kubevirt.io/v1",
+            "kind": "VirtualMachineInstance",
+            "metadata": {
+                "name": "runner",
+                "creationTimestamp": created,
+            },
+            "spec": {},
+        }))
+        .unwrap();
+        let age = vmi_age(&vmi).unwrap();
+        assert!(age >= Duration::from_secs(2 * 60 * 60));
+        assert!(age < Duration::from_secs(3 * 60 * 60));
+
+        vmi.metadata.creation_timestamp = None;
+        assert_eq!(None, vmi_age(&vmi));
+    }
+
+    #[test]
+    fn test_vmi_state_healthy() {
+        let stall_timeout = Duration::from_secs(60);
+        let mut state = VmiState::default();
+        assert!(state.is_healthy(stall_timeout));
+
+        state.watch_failing = true;
+        assert!(!state.is_healthy(stall_timeout));
+
+        state.watch_failing = false;
+        state.phase = Some("Failed".to_string());
+        assert!(!state.is_healthy(stall_timeout));
+
+        state.phase = Some("Running".to_string());
+        state.last_progress = Some(Instant::now());
+        assert!(state.is_healthy(stall_timeout));
+
+        state.last_progress = Instant::now().checked_sub(Duration::from_secs(120));
+        assert!(!state.is_healthy(stall_timeout));
+    }
+
+    #[test]
+    fn test_pulling_images() {
+        let pod: Pod = serde_json::from_value(serde_json::json!({
+            "status": {
+                "initContainerStatuses": [
+                    {
+                        "name": "volumecontainerdisk-init",
+                        "image": "quay.io/example/disk:latest",
+                        "imageID": "",
+                        "ready": false,
+                        "restartCount": 0,
+                        "state": { "waiting": { "reason": "PodInitializing" } },
+                    },
+                ],
+                "containerStatuses": [
+                    {
+                        "name": "compute",
+                        "image": "quay.io/kubevirt/virt-launcher:v1.0.0",
+                        "imageID": "quay.io/kubevirt/virt-launcher@sha256:abc",
+                        "ready": false,
+                        "restartCount": 0,
+                        "state": { "waiting": { "reason": "ContainerCreating" } },
+                    },
+                ],
+            },
+        }))
+        .unwrap();
+
+        assert_eq!(vec!["quay.io/example/disk:latest"], pulling_images(&pod));
+        assert!(pulling_images(&Pod::default()).is_empty());
+    }
+}